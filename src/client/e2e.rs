@@ -0,0 +1,244 @@
+//! 隧道端到端加密 (E2E)
+//!
+//! 在客户端与访问端 (`cec-tunnel connect`) 之间对隧道数据加密，服务端只转发密文。
+//!
+//! 握手：双方各发送 `MAGIC || 32 字节随机盐`，以共享密钥为输入、
+//! `访问端盐 || 客户端盐` 为盐，经 HKDF-SHA256 为两个方向各派生一个 ChaCha20-Poly1305 密钥。
+//! 双方都贡献随机数，服务端无法重放旧会话。
+//!
+//! 数据帧：`u16 密文长度 (大端) || 密文 (含 16 字节认证标签)`，nonce 为每个方向独立递增的计数器。
+//! 一个方向结束时发送明文为空的结束帧，未收到结束帧就遇到 EOF 视为数据流被截断，
+//! 服务端无法在不被发现的情况下提前结束任一方向。
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAGIC: &[u8; 8] = b"CECE2E01";
+const SALT_LEN: usize = 32;
+const HELLO_LEN: usize = MAGIC.len() + SALT_LEN;
+/// 单帧最大明文长度
+const MAX_PLAINTEXT: usize = 16 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 共享密钥的最小长度
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// 握手中的角色，决定各方向使用的密钥
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// 隧道客户端，连接本地服务的一侧
+    Client,
+    /// 访问端，`cec-tunnel connect`
+    Visitor,
+}
+
+pub fn check_secret(secret: &str) -> Result<()> {
+    if secret.len() < MIN_SECRET_LEN {
+        anyhow::bail!("E2E 密钥过短，至少需要 {} 个字符", MIN_SECRET_LEN);
+    }
+    Ok(())
+}
+
+/// 单方向的加密状态
+struct Direction {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Direction {
+    fn derive(secret: &[u8], salt: &[u8], info: &'static [u8]) -> Result<Self> {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(secret);
+        let info = [info];
+        let okm = prk
+            .expand(&info, &CHACHA20_POLY1305)
+            .map_err(|_| anyhow::anyhow!("E2E 密钥派生失败"))?;
+        Ok(Self {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            counter: 0,
+        })
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn seal(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        let nonce = self.next_nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), buf)
+            .map_err(|_| anyhow::anyhow!("E2E 加密失败"))
+    }
+
+    fn open<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8]> {
+        let nonce = self.next_nonce();
+        self.key
+            .open_in_place(nonce, Aad::empty(), buf)
+            .map_err(|_| anyhow::anyhow!("E2E 解密失败: 密钥不一致或数据被篡改"))
+    }
+}
+
+/// 在明文连接和密文流之间双向转发，直到两个方向都结束
+///
+/// 出错 (包括数据流被截断) 时重置明文连接，本地应用不会把不完整的数据当作正常结束。
+pub async fn bridge<C>(mut plain: TcpStream, cipher: C, secret: &str, role: Role) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let result = bridge_inner(&mut plain, cipher, secret, role).await;
+    if result.is_err() {
+        let _ = plain.set_zero_linger();
+    }
+    result
+}
+
+async fn bridge_inner<C>(plain: &mut TcpStream, cipher: C, secret: &str, role: Role) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut plain_r, mut plain_w) = plain.split();
+    let (mut cipher_r, mut cipher_w) = tokio::io::split(cipher);
+
+    // 交换握手
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| anyhow::anyhow!("系统随机数生成失败"))?;
+    let mut hello = Vec::with_capacity(HELLO_LEN);
+    hello.extend_from_slice(MAGIC);
+    hello.extend_from_slice(&salt);
+    cipher_w.write_all(&hello).await?;
+
+    let mut peer = [0u8; HELLO_LEN];
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, cipher_r.read_exact(&mut peer))
+        .await
+        .context("E2E 握手超时")?
+        .context("E2E 握手失败")?;
+    if &peer[..MAGIC.len()] != MAGIC {
+        anyhow::bail!("E2E 握手失败: 对端未启用 E2E 加密");
+    }
+    let peer_salt = &peer[MAGIC.len()..];
+
+    let mut hkdf_salt = Vec::with_capacity(SALT_LEN * 2);
+    match role {
+        Role::Visitor => {
+            hkdf_salt.extend_from_slice(&salt);
+            hkdf_salt.extend_from_slice(peer_salt);
+        }
+        Role::Client => {
+            hkdf_salt.extend_from_slice(peer_salt);
+            hkdf_salt.extend_from_slice(&salt);
+        }
+    }
+    let v2c = Direction::derive(
+        secret.as_bytes(),
+        &hkdf_salt,
+        b"cec-tunnel e2e visitor->client",
+    )?;
+    let c2v = Direction::derive(
+        secret.as_bytes(),
+        &hkdf_salt,
+        b"cec-tunnel e2e client->visitor",
+    )?;
+    let (mut seal, mut open) = match role {
+        Role::Visitor => (v2c, c2v),
+        Role::Client => (c2v, v2c),
+    };
+
+    // 明文 -> 密文
+    let encrypt = async {
+        let mut buf = vec![0u8; MAX_PLAINTEXT];
+        loop {
+            let n = plain_r.read(&mut buf).await?;
+            // n 为 0 时发送空的结束帧
+            let mut frame = buf[..n].to_vec();
+            seal.seal(&mut frame)?;
+            cipher_w
+                .write_all(&(frame.len() as u16).to_be_bytes())
+                .await?;
+            cipher_w.write_all(&frame).await?;
+            if n == 0 {
+                break;
+            }
+        }
+        cipher_w.shutdown().await?;
+        anyhow::Ok(())
+    };
+
+    // 密文 -> 明文
+    let decrypt = async {
+        loop {
+            let mut len = [0u8; 2];
+            cipher_r
+                .read_exact(&mut len)
+                .await
+                .context("E2E 数据流被截断: 未收到结束帧")?;
+            let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+            cipher_r
+                .read_exact(&mut frame)
+                .await
+                .context("E2E 数据流被截断")?;
+            let plaintext = open.open(&mut frame)?;
+            if plaintext.is_empty() {
+                break;
+            }
+            plain_w.write_all(plaintext).await?;
+        }
+        plain_w.shutdown().await?;
+        anyhow::Ok(())
+    };
+
+    // 两个方向各自结束 (半关闭)，任一方向出错时结束整个连接
+    tokio::try_join!(encrypt, decrypt).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (a, b) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (a.unwrap(), b.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn half_closed_request_gets_reply() {
+        let (mut app, visitor_plain) = tcp_pair().await;
+        let (client_plain, mut service) = tcp_pair().await;
+        let (visitor_cipher, client_cipher) = tokio::io::duplex(64 * 1024);
+        let visitor = tokio::spawn(bridge(visitor_plain, visitor_cipher, SECRET, Role::Visitor));
+        let client = tokio::spawn(bridge(client_plain, client_cipher, SECRET, Role::Client));
+
+        // 本地服务读到请求结束后才回复
+        let server = tokio::spawn(async move {
+            let mut request = Vec::new();
+            service.read_to_end(&mut request).await.unwrap();
+            service.write_all(b"reply:").await.unwrap();
+            service.write_all(&request).await.unwrap();
+            service.shutdown().await.unwrap();
+        });
+
+        app.write_all(b"request").await.unwrap();
+        app.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        app.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply:request");
+
+        server.await.unwrap();
+        visitor.await.unwrap().unwrap();
+        client.await.unwrap().unwrap();
+    }
+}
@@ -2,14 +2,19 @@
 //!
 //! 内网穿透客户端，连接到服务端建立反向隧道。
 
+mod e2e;
+mod policy;
+mod tls;
 mod tunnel;
+mod visitor;
 
 #[path = "../common/mod.rs"]
 mod common;
 
-use anyhow::Result;
-use clap::Parser;
-use tracing::info;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use common::protocol::TunnelConfig;
+use tracing::{info, warn};
 
 #[derive(Parser, Debug)]
 #[command(name = "cec-tunnel")]
@@ -31,6 +36,27 @@ CEC Tunnel 是一个轻量级的内网穿透客户端。
   # 指定名称和隧道 (-n 和 -t 可选)
   cec-tunnel -s wss://server:9999 -n "office" -t tcp:22:10022
 
+  # 自签证书: 指定 CA 或证书指纹
+  cec-tunnel -s wss://server:9999 --ca-file /etc/cec-tunnel/cert.pem
+  cec-tunnel -s wss://server:9999 --pin-sha256 AB:CD:...:EF
+
+  # 仅允许指定网段访问
+  cec-tunnel -s wss://server:9999 -t 'tcp:22:10022?allow=203.0.113.0/24'
+
+  # 端到端加密: 客户端与访问端使用相同密钥，服务端只转发密文
+  cec-tunnel -s wss://server:9999 -t 'tcp:22:10022?e2e' --e2e-secret <密钥>
+  cec-tunnel connect --remote server:10022 --listen 127.0.0.1:2222 --e2e-secret <密钥>
+
+  # 私密隧道: 不开放服务端端口，只有持相同密钥的访问端可以连接
+  cec-tunnel -s wss://server:9999 -n office --secret-file ssh.key -t 'tcp:22:0?sk&name=ssh'
+  cec-tunnel -s wss://server:9999 --secret-file ssh.key visit office/ssh --listen 127.0.0.1:2222
+
+  # 压缩不小于 512 字节的数据 (适合按流量计费的链路)
+  cec-tunnel -s wss://server:9999 -t 'tcp:6379:10379?compress=512'
+
+  # 本地转发 (类似 ssh -L): 经服务端连接其他客户端的隧道，可同时转发多个
+  cec-tunnel -s wss://server:9999 visit -L 2222:office/ssh?sk=<密钥> -L 8080:dev/tunnel-10080
+
   # 暴露多个服务
   cec-tunnel -s wss://tunnel.example.com:9999 \
              -n "dev-server" \
@@ -38,27 +64,108 @@ CEC Tunnel 是一个轻量级的内网穿透客户端。
              -t tcp:3306:10306
 "#)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// 服务器地址 (ws://host:9998 明文, wss://host:9999 加密)
     #[arg(short, long, default_value = "ws://localhost:9998")]
     server: String,
 
-    /// 客户端名称
-    #[arg(short, long, default_value = "tunnel-client")]
-    name: String,
+    /// 客户端名称 [默认: tunnel-client，visit 模式为 visitor-<主机名>]
+    #[arg(short, long)]
+    name: Option<String>,
 
-    /// 隧道配置: type:local_port:remote_port
+    /// 隧道配置: type:local_port:remote_port，可追加 ?allow=CIDR,...&deny=CIDR,... 限制访问来源，
+    /// ?sk=密钥&name=名称 为私密隧道 (只写 ?sk 时使用 --secret-file 中的密钥)，
+    /// ?compress[=阈值] 压缩数据
     #[arg(short, long)]
     tunnel: Vec<String>,
 
+    /// 私密隧道密钥文件，供 -t 中不带密钥的 ?sk、服务端 API 添加的私密隧道和 visit 使用，
+    /// 避免密钥出现在命令行和进程列表中
+    #[arg(long)]
+    secret_file: Option<String>,
+
     /// 认证 Token
     #[arg(long)]
     token: Option<String>,
 
+    /// 允许服务端下发隧道连接的本地目标，可重复指定，格式 主机[:端口]，
+    /// 如 127.0.0.1:22、10.0.0.0/24:80-443、db.local:3306 (未指定时不限制，-t 的目标始终允许)
+    #[arg(long = "allow-target")]
+    allow_targets: Vec<String>,
+
+    /// 端到端加密密钥 (至少 16 个字符)，用于带 ?e2e 选项的隧道，访问端需使用相同密钥
+    #[arg(long)]
+    e2e_secret: Option<String>,
+
+    /// 客户端证书 (PEM)，服务端配置 --client-ca 时使用，证书 CN 需与 -n 一致
+    #[arg(long, requires = "key")]
+    cert: Option<String>,
+
+    /// 客户端私钥 (PEM)
+    #[arg(long, requires = "cert")]
+    key: Option<String>,
+
+    /// 信任的 CA 证书 (PEM)，用于自签服务端证书，替代内置根证书
+    #[arg(long)]
+    ca_file: Option<String>,
+
+    /// 服务端证书 SHA-256 指纹，可重复指定 (openssl x509 -noout -fingerprint -sha256)
+    #[arg(long = "pin-sha256", conflicts_with = "ca_file")]
+    pins: Vec<String>,
+
+    /// 不校验服务端证书 (仅用于测试)
+    #[arg(long, conflicts_with_all = ["ca_file", "pins"])]
+    insecure: bool,
+
     /// 日志级别
     #[arg(long, default_value = "info")]
     log_level: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 访问端：在本地监听，将连接端到端加密后转发到 e2e 隧道的服务端端口
+    Connect {
+        /// 隧道的服务端地址，如 tunnel.example.com:10022
+        #[arg(long)]
+        remote: String,
+
+        /// 本地监听地址
+        #[arg(long, default_value = "127.0.0.1:2222")]
+        listen: String,
+
+        /// 端到端加密密钥，与隧道客户端的 --e2e-secret 相同
+        #[arg(long)]
+        e2e_secret: String,
+    },
+    /// 访问端：在本地监听，经服务端连接其他客户端的隧道 (全局参数如 -s、--token 写在 visit 之前)
+    Visit {
+        /// 目标隧道，格式 客户端名称/隧道名称，监听地址由 --listen 指定
+        #[arg(required_unless_present = "forwards")]
+        target: Option<String>,
+
+        /// 本地监听地址
+        #[arg(short, long, default_value = "127.0.0.1:2222")]
+        listen: String,
+
+        /// 本地转发，可重复指定，格式 [绑定地址:]端口:客户端名称/隧道名称，
+        /// 可追加 ?sk=密钥&e2e，如 2222:office/ssh?sk=xxx
+        #[arg(short = 'L', long = "forward")]
+        forwards: Vec<String>,
+
+        /// 私密隧道密钥，与隧道配置中的 sk 相同，用于未单独指定 sk 的目标 (未指定时读取 --secret-file)
+        #[arg(long)]
+        secret_key: Option<String>,
+
+        /// 端到端加密密钥，与隧道客户端的 --e2e-secret 相同；指定后 TARGET 启用 e2e，
+        /// -L 转发需追加 ?e2e
+        #[arg(long)]
+        e2e_secret: Option<String>,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // rustls 0.23+ 需要显式安装 CryptoProvider
@@ -77,6 +184,49 @@ async fn main() -> Result<()> {
 
     info!("CEC Tunnel Client v{}", env!("CARGO_PKG_VERSION"));
 
+    let secret = args.secret_file.as_deref().map(read_secret).transpose()?;
+    let mut name = args.name;
+    let mut e2e_secret = args.e2e_secret;
+    let visitors = match args.command {
+        Some(Command::Connect {
+            remote,
+            listen,
+            e2e_secret,
+        }) => return visitor::connect(&listen, &remote, &e2e_secret).await,
+        Some(Command::Visit {
+            target,
+            listen,
+            forwards,
+            secret_key,
+            e2e_secret: visit_e2e_secret,
+        }) => {
+            let secret_key = secret_key.or_else(|| secret.clone());
+            let mut configs = forwards
+                .iter()
+                .map(|f| visitor::VisitorConfig::parse(f, secret_key.as_deref()))
+                .collect::<Result<Vec<_>>>()?;
+            if let Some(target) = target {
+                let e2e = visit_e2e_secret.is_some();
+                configs.push(visitor::VisitorConfig::new(
+                    &target, listen, secret_key, e2e,
+                )?);
+            }
+            if visit_e2e_secret.is_some() {
+                e2e_secret = visit_e2e_secret;
+            }
+            // 避免与隧道客户端使用相同的默认名称而互相顶替
+            name.get_or_insert_with(|| {
+                format!(
+                    "visitor-{}",
+                    hostname::get().unwrap_or_default().to_string_lossy()
+                )
+            });
+            configs
+        }
+        None => Vec::new(),
+    };
+    let name = name.unwrap_or_else(|| "tunnel-client".to_string());
+
     // 自动拼接 /tunnel 路径，用户无需手动添加
     let server_url = if args.server.ends_with("/tunnel") {
         args.server.clone()
@@ -87,7 +237,7 @@ async fn main() -> Result<()> {
 
     info!("服务器: {}", server_url);
 
-    if args.tunnel.is_empty() {
+    if args.tunnel.is_empty() && visitors.is_empty() {
         info!("未指定隧道，仅建立连接，等待服务端分配...");
     } else {
         for t in &args.tunnel {
@@ -95,6 +245,49 @@ async fn main() -> Result<()> {
         }
     }
 
-    let client = tunnel::TunnelClient::new(&server_url, &args.name, &args.tunnel, args.token)?;
+    let tls = tls::TlsOptions {
+        cert: args.cert,
+        key: args.key,
+        ca_file: args.ca_file,
+        pins: args.pins,
+        insecure: args.insecure,
+    };
+    let policy = policy::TargetPolicy::parse(&args.allow_targets)?;
+    if policy.is_enabled() {
+        info!("本地目标限制: {}", args.allow_targets.join(", "));
+    }
+    let tunnel_configs = args
+        .tunnel
+        .iter()
+        .filter_map(|t| {
+            let config = TunnelConfig::parse(t, secret.as_deref());
+            if config.is_none() {
+                warn!("忽略无效的隧道配置: {}", t);
+            }
+            config
+        })
+        .collect();
+    let client = tunnel::TunnelClient::new(
+        &server_url,
+        &name,
+        tunnel_configs,
+        args.token,
+        tls.connector()?,
+        policy,
+        e2e_secret,
+    )?
+    .with_visitors(visitors)?
+    .with_secret(secret);
     client.run().await
 }
+
+/// 读取私密隧道密钥文件，首尾空白会被忽略
+fn read_secret(path: &str) -> Result<String> {
+    let secret =
+        std::fs::read_to_string(path).with_context(|| format!("读取密钥文件 {} 失败", path))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        anyhow::bail!("密钥文件 {} 为空", path);
+    }
+    Ok(secret.to_string())
+}
@@ -1 +1,5 @@
+pub mod e2e;
+pub mod policy;
+pub mod tls;
 pub mod tunnel;
+pub mod visitor;
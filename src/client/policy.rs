@@ -0,0 +1,290 @@
+//! 本地目标访问策略 (--allow-target)
+//!
+//! 限制服务端可以让客户端连接的本地地址，防止服务端被攻破或管理 API Key 泄露后
+//! 借助 `AddTunnel` 访问整个内网。规则格式 `主机[:端口]`：
+//! - 主机: IP、CIDR、域名、`*.example.com` 或 `*`；IPv6 需加方括号，如 `[fd00::/8]:22`
+//! - 端口: 单个端口 `22`、范围 `8000-8100` 或 `*`，省略表示任意端口
+//!
+//! 未配置任何规则时不做限制。目标为域名时，先按域名匹配，再按解析后的 IP 匹配；
+//! 实际连接只使用通过检查的地址，避免 DNS 重绑定绕过。
+
+use anyhow::Result;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone)]
+enum HostPattern {
+    Any,
+    Net(IpNet),
+    /// 域名，以 `*.` 开头时匹配其子域名
+    Name(String),
+}
+
+#[derive(Debug, Clone)]
+struct TargetRule {
+    host: HostPattern,
+    ports: (u16, u16),
+}
+
+impl TargetRule {
+    fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let invalid = || anyhow::anyhow!("无效的 --allow-target 规则: {}", spec);
+
+        // 不带端口的 IP/CIDR (含未加方括号的 IPv6)
+        if let Some(net) = parse_net(spec) {
+            return Ok(Self {
+                host: HostPattern::Net(net),
+                ports: (0, u16::MAX),
+            });
+        }
+
+        let (host, ports) = if let Some(rest) = spec.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest.strip_prefix(':') {
+                Some(ports) => (host, Some(ports)),
+                None if rest.is_empty() => (host, None),
+                None => return Err(invalid()),
+            }
+        } else {
+            match spec.rsplit_once(':') {
+                // 带端口的 IPv6 必须加方括号
+                Some((host, _)) if host.contains(':') => return Err(invalid()),
+                Some((host, ports)) => (host, Some(ports)),
+                None => (spec, None),
+            }
+        };
+
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(net) = parse_net(host) {
+            HostPattern::Net(net)
+        } else if !host.is_empty() && !host.contains(['/', ':', '[', ']']) {
+            HostPattern::Name(host.to_ascii_lowercase())
+        } else {
+            return Err(invalid());
+        };
+
+        let ports = match ports {
+            None | Some("*") => (0, u16::MAX),
+            Some(p) => match p.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| invalid())?,
+                    end.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let port = p.parse().map_err(|_| invalid())?;
+                    (port, port)
+                }
+            },
+        };
+        if ports.0 > ports.1 {
+            return Err(invalid());
+        }
+
+        Ok(Self { host, ports })
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        port >= self.ports.0 && port <= self.ports.1
+    }
+
+    fn matches_name(&self, host: &str, port: u16) -> bool {
+        let host = host.to_ascii_lowercase();
+        let matched = match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Net(_) => false,
+            HostPattern::Name(name) => match name.strip_prefix("*.") {
+                Some(suffix) => host
+                    .strip_suffix(suffix)
+                    .is_some_and(|prefix| prefix.ends_with('.')),
+                None => *name == host,
+            },
+        };
+        matched && self.matches_port(port)
+    }
+
+    fn matches_ip(&self, ip: IpAddr, port: u16) -> bool {
+        let matched = match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Net(net) => net.contains(&ip.to_canonical()),
+            HostPattern::Name(_) => false,
+        };
+        matched && self.matches_port(port)
+    }
+}
+
+fn parse_net(s: &str) -> Option<IpNet> {
+    s.parse::<IpNet>()
+        .ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// 允许连接的本地目标
+#[derive(Debug, Clone, Default)]
+pub struct TargetPolicy {
+    rules: Vec<TargetRule>,
+}
+
+impl TargetPolicy {
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let rules = specs
+            .iter()
+            .map(|s| TargetRule::parse(s))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// 放行指定目标 (本地 -t 配置的隧道)
+    pub fn allow_exact(&mut self, host: &str, port: u16) {
+        let host = match parse_net(host) {
+            Some(net) => HostPattern::Net(net),
+            None => HostPattern::Name(host.to_ascii_lowercase()),
+        };
+        self.rules.push(TargetRule {
+            host,
+            ports: (port, port),
+        });
+    }
+
+    fn matches_name(&self, host: &str, port: u16) -> bool {
+        self.rules.iter().any(|r| r.matches_name(host, port))
+    }
+
+    fn matches_ip(&self, ip: IpAddr, port: u16) -> bool {
+        self.rules.iter().any(|r| r.matches_ip(ip, port))
+    }
+
+    /// 检查服务端下发的隧道目标
+    pub async fn check(&self, host: &str, port: u16) -> Result<(), String> {
+        if !self.is_enabled() || self.matches_name(host, port) {
+            return Ok(());
+        }
+        self.resolve(host, port).await.map(|_| ())
+    }
+
+    /// 解析目标地址，只返回策略允许的地址
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("解析 {}:{} 失败: {}", host, port, e))?
+            .collect();
+        if !self.is_enabled() || self.matches_name(host, port) {
+            return Ok(addrs);
+        }
+        let allowed: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|a| self.matches_ip(a.ip(), port))
+            .collect();
+        if allowed.is_empty() {
+            return Err(format!(
+                "目标 {}:{} 不在 --allow-target 允许范围内",
+                host, port
+            ));
+        }
+        Ok(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(specs: &[&str]) -> TargetPolicy {
+        TargetPolicy::parse(&specs.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn host_cidr_and_port_ranges() {
+        let p = policy(&["192.168.1.10:22", "10.0.0.0/8:8000-8100", "[fd00::/8]:*"]);
+        assert!(p.check("192.168.1.10", 22).await.is_ok());
+        assert!(p.check("192.168.1.10", 23).await.is_err());
+        assert!(p.check("192.168.1.11", 22).await.is_err());
+        assert!(p.check("10.1.2.3", 8000).await.is_ok());
+        assert!(p.check("10.1.2.3", 8100).await.is_ok());
+        assert!(p.check("10.1.2.3", 7999).await.is_err());
+        assert!(p.check("10.1.2.3", 8101).await.is_err());
+        assert!(p.check("fd12::1", 443).await.is_ok());
+        assert!(p.check("fe80::1", 443).await.is_err());
+
+        // 不带端口的规则匹配任意端口
+        let p = policy(&["172.16.0.0/12", "2001:db8::1"]);
+        assert!(p.check("172.20.0.1", 1).await.is_ok());
+        assert!(p.check("2001:db8::1", 65535).await.is_ok());
+        assert!(p.check("172.32.0.1", 1).await.is_err());
+    }
+
+    #[test]
+    fn domain_patterns() {
+        let p = policy(&["*.internal.example.com:443", "db.example.com"]);
+        assert!(p.matches_name("api.internal.example.com", 443));
+        assert!(p.matches_name("API.Internal.Example.com", 443));
+        assert!(!p.matches_name("internal.example.com", 443));
+        assert!(!p.matches_name("evilinternal.example.com", 443));
+        assert!(!p.matches_name("api.internal.example.com", 80));
+        assert!(p.matches_name("db.example.com", 5432));
+        assert!(!p.matches_name("x.db.example.com", 5432));
+    }
+
+    #[tokio::test]
+    async fn hostname_checked_by_resolved_address() {
+        // localhost 解析为回环地址，不在允许的网段内
+        let p = policy(&["10.0.0.0/8"]);
+        assert!(p.check("localhost", 22).await.is_err());
+        assert!(p.resolve("localhost", 22).await.is_err());
+
+        // 按 IP 放行时只返回通过检查的地址
+        let p = policy(&["127.0.0.1:22"]);
+        let addrs = p.resolve("localhost", 22).await.unwrap();
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(|a| a.ip() == IpAddr::from([127, 0, 0, 1])));
+        assert!(p.check("localhost", 23).await.is_err());
+
+        // IPv4 映射地址按 IPv4 规则匹配
+        assert!(p.check("::ffff:127.0.0.1", 22).await.is_ok());
+        assert!(policy(&["10.0.0.0/8"])
+            .check("::ffff:127.0.0.1", 22)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn allow_exact_and_disabled() {
+        let mut p = TargetPolicy::default();
+        assert!(!p.is_enabled());
+        assert!(p.check("192.168.1.1", 3389).await.is_ok());
+
+        p.allow_exact("127.0.0.1", 22);
+        assert!(p.is_enabled());
+        assert!(p.check("127.0.0.1", 22).await.is_ok());
+        assert!(p.check("127.0.0.1", 23).await.is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_specs() {
+        for bad in [
+            "",
+            "host:",
+            "host:abc",
+            "host:70000",
+            "host:9000-8000",
+            "host:1-2-3",
+            "10.0.0.0/33:22",
+            "fd00::/8:22",
+            "[fd00::/8",
+            "[fd00::/8]22",
+            "[]:22",
+            "a/b",
+        ] {
+            assert!(
+                TargetPolicy::parse(&[bad.to_string()]).is_err(),
+                "{:?}",
+                bad
+            );
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! 客户端 TLS 配置
+//!
+//! 服务端证书的校验方式（三选一）：
+//! - 默认：webpki 内置根证书，或 `--ca-file` 指定的 CA；`--ca-file` 中的证书也可以直接是
+//!   服务端的自签证书
+//! - `--pin-sha256`：只接受指纹匹配的服务端证书，适合自签证书
+//! - `--insecure`：不校验服务端证书，仅用于测试
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{verify_server_name, WebPkiServerVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::ParsedCertificate;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_tungstenite::Connector;
+use tracing::warn;
+
+use crate::common::tls::{load_certs, load_key};
+
+/// wss:// 连接的 TLS 选项
+#[derive(Debug, Default)]
+pub struct TlsOptions {
+    /// 客户端证书 (PEM)，用于服务端 --client-ca 双向认证
+    pub cert: Option<String>,
+    /// 客户端私钥 (PEM)
+    pub key: Option<String>,
+    /// 信任的 CA 证书 (PEM)，替代内置根证书
+    pub ca_file: Option<String>,
+    /// 服务端证书 SHA-256 指纹
+    pub pins: Vec<String>,
+    /// 不校验服务端证书
+    pub insecure: bool,
+}
+
+impl TlsOptions {
+    /// 构建自定义 rustls 连接器；未指定任何选项时返回 None，使用默认配置
+    pub fn connector(&self) -> Result<Option<Connector>> {
+        if self.cert.is_none()
+            && self.key.is_none()
+            && self.ca_file.is_none()
+            && self.pins.is_empty()
+            && !self.insecure
+        {
+            return Ok(None);
+        }
+
+        let builder = ClientConfig::builder();
+        let builder = if self.insecure {
+            warn!("已启用 --insecure，不校验服务端证书，连接可能被中间人劫持");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(FingerprintVerifier::new(None)))
+        } else if !self.pins.is_empty() {
+            let pins = self
+                .pins
+                .iter()
+                .map(|p| parse_fingerprint(p))
+                .collect::<Result<Vec<_>>>()?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(FingerprintVerifier::new(Some(pins))))
+        } else if let Some(path) = &self.ca_file {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(CaFileVerifier::new(
+                    load_certs(path)?,
+                    path,
+                )?))
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            builder.with_root_certificates(roots)
+        };
+
+        let config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .context("加载客户端证书失败")?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => anyhow::bail!("--cert 和 --key 必须同时指定"),
+        };
+
+        Ok(Some(Connector::Rustls(Arc::new(config))))
+    }
+}
+
+/// 解析 SHA-256 指纹，支持 `AB:CD:...`（openssl 输出格式）或连续十六进制，可带 `sha256:` 前缀
+fn parse_fingerprint(s: &str) -> Result<Vec<u8>> {
+    let hex: String = s
+        .trim()
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>();
+    match bytes {
+        Some(b) if b.len() == 32 => Ok(b),
+        _ => anyhow::bail!("无效的 SHA-256 指纹: {}", s),
+    }
+}
+
+/// `--ca-file` 校验：服务端证书与文件中某个证书完全相同时直接信任 (仍校验域名)，否则按 CA 校验证书链
+///
+/// openssl 生成的自签证书带有 CA:TRUE，webpki 不接受 CA 证书作为服务端证书
+/// (CaUsedAsEndEntity)，因此先按原样比对。
+#[derive(Debug)]
+struct CaFileVerifier {
+    certs: Vec<CertificateDer<'static>>,
+    webpki: Arc<WebPkiServerVerifier>,
+}
+
+impl CaFileVerifier {
+    fn new(certs: Vec<CertificateDer<'static>>, path: &str) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for ca in &certs {
+            roots
+                .add(ca.clone())
+                .with_context(|| format!("加载 CA 证书 {} 失败", path))?;
+        }
+        let webpki = WebPkiServerVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::new(rustls::crypto::ring::default_provider()),
+        )
+        .build()
+        .with_context(|| format!("加载 CA 证书 {} 失败", path))?;
+        Ok(Self { certs, webpki })
+    }
+}
+
+impl ServerCertVerifier for CaFileVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.certs.iter().any(|c| c.as_ref() == end_entity.as_ref()) {
+            verify_server_name(&ParsedCertificate::try_from(end_entity)?, server_name)?;
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.webpki
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/// 按证书指纹校验服务端证书；`pins` 为 None 时接受任意证书 (--insecure)
+///
+/// 不校验证书链和域名，但仍校验握手签名，确保对端持有证书私钥。
+#[derive(Debug)]
+struct FingerprintVerifier {
+    pins: Option<Vec<Vec<u8>>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl FingerprintVerifier {
+    fn new(pins: Option<Vec<Vec<u8>>>) -> Self {
+        Self {
+            pins,
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Some(pins) = &self.pins else {
+            return Ok(ServerCertVerified::assertion());
+        };
+        let fingerprint = digest(&SHA256, end_entity.as_ref());
+        if pins.iter().any(|p| p.as_slice() == fingerprint.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "服务端证书指纹与 --pin-sha256 不匹配".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    /// 与 `openssl req -x509 -subj /CN=localhost -addext subjectAltName=DNS:localhost` 相同，
+    /// 自签证书带有 CA:TRUE
+    fn self_signed_ca() -> CertificateDer<'static> {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.self_signed(&KeyPair::generate().unwrap()).unwrap().der().clone()
+    }
+
+    fn verify(verifier: &CaFileVerifier, cert: &CertificateDer<'_>, name: &str) -> bool {
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        verifier
+            .verify_server_cert(cert, &[], &name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    #[test]
+    fn ca_file_accepts_self_signed_server_cert() {
+        let cert = self_signed_ca();
+        let verifier = CaFileVerifier::new(vec![cert.clone()], "cert.pem").unwrap();
+        assert!(verify(&verifier, &cert, "localhost"));
+        assert!(!verify(&verifier, &cert, "example.com"));
+    }
+
+    #[test]
+    fn ca_file_rejects_other_self_signed_cert() {
+        let verifier = CaFileVerifier::new(vec![self_signed_ca()], "cert.pem").unwrap();
+        assert!(!verify(&verifier, &self_signed_ca(), "localhost"));
+    }
+}
@@ -3,30 +3,152 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, RwLock};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::Message, Connector};
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::common::auth;
+use crate::common::codec::{Codec, Encoded};
+use crate::common::compress;
+use crate::common::flow::{self, Window};
+use crate::common::half_close::{self, DataReceiver};
+use crate::common::protocol::{
+    capability, ClientInfo, CloseReason, StreamId, TunnelConfig, TunnelInfo, VisitorAuth,
+    WsMessage, PROTOCOL_VERSION,
+};
+use crate::common::queue::{self, SendQueue};
+use crate::e2e;
+use crate::policy::TargetPolicy;
+use crate::visitor::VisitorConfig;
+
+/// 不可恢复的错误（如认证失败），客户端不再自动重连
+#[derive(Debug)]
+pub struct FatalError(pub String);
+
+impl std::fmt::Display for FatalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for FatalError {}
+
+type BoxedRead = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWrite = Box<dyn AsyncWrite + Unpin + Send>;
+type Connections = Arc<RwLock<HashMap<StreamId, LocalConn>>>;
+/// VisitorAccept 的结果: (流 ID, 压缩阈值) 或失败原因
+type VisitorAccepted = Result<(StreamId, Option<u32>), String>;
+/// 等待 VisitorAccept 的访问端连接: request_id -> (结果通知, 本地连接)
+type PendingVisitors = Arc<RwLock<HashMap<String, (oneshot::Sender<VisitorAccepted>, LocalConn)>>>;
+
+/// 等待服务端回复 VisitorAccept 的超时
+const VISITOR_OPEN_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(15);
+
+/// 支持的最低服务端协议版本 (数字流 ID)
+const MIN_SERVER_PROTOCOL: u32 = 4;
+
+/// 当前已注册的 WebSocket 会话，访问端连接经由它发起
+#[derive(Clone)]
+struct Session {
+    tx: SendQueue,
+    /// 服务端 Hello 下发的 nonce，用于计算私密隧道密钥的 HMAC
+    nonce: String,
+    options: StreamOptions,
+}
+
+/// 本次会话协商的连接特性
+#[derive(Clone, Copy, Default)]
+struct StreamOptions {
+    flow_control: bool,
+    half_close: bool,
+    /// 连接本地服务失败时回复 ConnectionFailed，否则为 CloseConnection
+    report_failure: bool,
+    /// 可以压缩 Data，隧道还需配置压缩阈值
+    deflate: bool,
+}
+
+impl StreamOptions {
+    fn negotiated(capabilities: &[String]) -> Self {
+        let has = |name: &str| capabilities.iter().any(|c| c == name);
+        Self {
+            flow_control: has(capability::FLOW_CONTROL),
+            half_close: has(capability::HALF_CLOSE),
+            report_failure: has(capability::CONNECTION_FAILED),
+            deflate: has(capability::DEFLATE),
+        }
+    }
+
+    /// 连接本地服务失败时回复服务端的消息
+    fn failed(&self, conn_id: StreamId, reason: CloseReason, message: String) -> WsMessage {
+        if self.report_failure {
+            WsMessage::ConnectionFailed {
+                conn_id,
+                reason,
+                message,
+            }
+        } else {
+            WsMessage::CloseConnection { conn_id, reason }
+        }
+    }
+}
+
+/// 经服务端转发的本地连接
+struct LocalConn {
+    /// 发往本地连接的数据，积压量受 window 限制，空数据表示服务端已关闭写方向
+    tx: mpsc::Sender<Vec<u8>>,
+    window: Arc<Window>,
+}
 
-use crate::common::protocol::{ClientInfo, TunnelConfig, TunnelInfo, WsMessage};
+impl LocalConn {
+    fn new(flow_control: bool) -> (Self, DataReceiver) {
+        let (tx, rx) = flow::data_channel();
+        let window = Arc::new(Window::new(flow_control));
+        (Self { tx, window }, rx)
+    }
+}
+
+impl Drop for LocalConn {
+    fn drop(&mut self) {
+        // 唤醒等待额度的读取任务，连接移除后额度不会再归还
+        self.window.close();
+    }
+}
 
 pub struct TunnelClient {
     server_url: String,
+    token: Option<String>,
+    connector: Option<Connector>,
     client_info: ClientInfo,
     tunnel_configs: Vec<TunnelConfig>,
+    /// 允许连接的本地目标
+    policy: Arc<TargetPolicy>,
+    /// 端到端加密密钥，e2e 隧道使用
+    e2e_secret: Option<Arc<str>>,
+    /// --secret-file 中的私密隧道密钥，用于校验访问端
+    secret: Option<String>,
+    /// 访问端转发，经服务端连接其他客户端的隧道
+    visitors: Vec<VisitorConfig>,
+    session: Arc<RwLock<Option<Session>>>,
+    /// 等待服务端响应的访问端连接
+    pending_visitors: PendingVisitors,
     tunnels: Arc<RwLock<HashMap<String, TunnelInfo>>>,
-    connections: Arc<RwLock<HashMap<String, mpsc::UnboundedSender<Vec<u8>>>>>,
+    connections: Connections,
 }
 
 impl TunnelClient {
     pub fn new(
         server: &str,
         name: &str,
-        tunnel_strs: &[String],
-        _token: Option<String>,
+        tunnel_configs: Vec<TunnelConfig>,
+        token: Option<String>,
+        connector: Option<Connector>,
+        mut policy: TargetPolicy,
+        e2e_secret: Option<String>,
     ) -> Result<Self> {
         let hostname = hostname::get()?.to_string_lossy().to_string();
 
@@ -40,26 +162,86 @@ impl TunnelClient {
             local_ip: get_local_ip(),
         };
 
-        let tunnel_configs = tunnel_strs
-            .iter()
-            .filter_map(|t| TunnelConfig::parse(t))
-            .collect();
+        if let Some(secret) = &e2e_secret {
+            e2e::check_secret(secret)?;
+        } else if tunnel_configs.iter().any(|c| c.e2e) {
+            anyhow::bail!("隧道启用了 e2e，但未指定 --e2e-secret");
+        }
+
+        // 本地 -t 配置的隧道目标始终允许
+        if policy.is_enabled() {
+            for config in &tunnel_configs {
+                policy.allow_exact(&config.local_addr, config.local_port);
+            }
+        }
 
         Ok(Self {
             server_url: server.to_string(),
+            token,
+            connector,
             client_info,
             tunnel_configs,
+            policy: Arc::new(policy),
+            e2e_secret: e2e_secret.map(Arc::from),
+            secret: None,
+            visitors: Vec::new(),
+            session: Arc::new(RwLock::new(None)),
+            pending_visitors: Arc::new(RwLock::new(HashMap::new())),
             tunnels: Arc::new(RwLock::new(HashMap::new())),
             connections: Arc::new(RwLock::new(HashMap::new())),
         })
     }
 
+    /// 访问其他客户端的隧道，在本地监听并经服务端转发
+    pub fn with_visitors(mut self, visitors: Vec<VisitorConfig>) -> Result<Self> {
+        if visitors.iter().any(|v| v.e2e) && self.e2e_secret.is_none() {
+            anyhow::bail!("访问端启用了 e2e，但未指定 --e2e-secret");
+        }
+        self.visitors = visitors;
+        Ok(self)
+    }
+
+    /// --secret-file 中的密钥，校验访问服务端下发的私密隧道 (及 -t 中不带密钥的 ?sk) 的访问端
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    /// 私密隧道的密钥：本地 -t 配置的同名隧道的密钥，其次为 --secret-file 中的密钥
+    fn secret_key(&self, tunnel: &TunnelInfo) -> Option<&str> {
+        self.tunnel_configs
+            .iter()
+            .filter(|c| c.local_addr == tunnel.local_addr && c.local_port == tunnel.local_port)
+            .filter(|c| c.name.as_ref().is_none_or(|name| *name == tunnel.name))
+            .find_map(|c| c.secret_key.as_deref())
+            .or(self.secret.as_deref())
+    }
+
     pub async fn run(&self) -> Result<()> {
+        // 访问端监听在重连期间保持不变，只在绑定失败时退出
+        let visitors = async {
+            futures::future::try_join_all(self.visitors.iter().map(|v| self.serve_visitor(v)))
+                .await?;
+            std::future::pending::<Result<()>>().await
+        };
+        tokio::select! {
+            r = visitors => r,
+            r = self.reconnect_loop() => r,
+        }
+    }
+
+    async fn reconnect_loop(&self) -> Result<()> {
         loop {
-            match self.connect_and_run().await {
+            let result = self.connect_and_run().await;
+            *self.session.write().await = None;
+            match result {
                 Ok(_) => {
                     info!("连接已关闭，5秒后重连...");
                 }
+                Err(e) if e.downcast_ref::<FatalError>().is_some() => {
+                    error!("{}，停止重连", e);
+                    return Err(e);
+                }
                 Err(e) => {
                     error!("连接错误: {}，5秒后重连...", e);
                 }
@@ -71,45 +253,98 @@ impl TunnelClient {
     async fn connect_and_run(&self) -> Result<()> {
         info!("正在连接 {}...", self.server_url);
 
-        let (ws_stream, _) = connect_async(&self.server_url).await?;
+        let (ws_stream, _) =
+            connect_async_tls_with_config(&self.server_url, None, false, self.connector.clone())
+                .await?;
         let (mut write, mut read) = ws_stream.split();
 
         info!("已连接到服务器");
 
-        // 发送注册消息
+        // 等待服务端握手挑战
+        let hello = tokio::time::timeout(tokio::time::Duration::from_secs(10), read.next()).await;
+        let nonce = match hello {
+            Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str(&text) {
+                Ok(WsMessage::Hello {
+                    protocol_version, ..
+                }) if protocol_version < MIN_SERVER_PROTOCOL => {
+                    return Err(FatalError(format!(
+                        "服务端协议版本 {} 过旧 (需要 {})，请升级 cec-tunnel-server",
+                        protocol_version, MIN_SERVER_PROTOCOL
+                    ))
+                    .into())
+                }
+                Ok(WsMessage::Hello {
+                    protocol_version,
+                    nonce,
+                }) => {
+                    if protocol_version != PROTOCOL_VERSION {
+                        info!(
+                            "服务端协议版本 {}，客户端协议版本 {}",
+                            protocol_version, PROTOCOL_VERSION
+                        );
+                    }
+                    nonce
+                }
+                _ => {
+                    return Err(FatalError(
+                        "握手失败: 服务端协议不兼容，请升级 cec-tunnel-server".to_string(),
+                    )
+                    .into())
+                }
+            },
+            Ok(Some(Err(e))) => return Err(e.into()),
+            Ok(_) => return Err(anyhow::anyhow!("握手失败: 连接已关闭")),
+            // 旧版服务端 (协议版本 1) 不发送 Hello，重连也不会成功
+            Err(_) => {
+                return Err(FatalError(
+                    "握手失败: 服务端未发送 Hello，请升级 cec-tunnel-server".to_string(),
+                )
+                .into())
+            }
+        };
+
+        // 发送握手应答和注册消息
+        let auth_msg = WsMessage::Auth {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: self.client_info.name.clone(),
+            mac: self
+                .token
+                .as_deref()
+                .map(|t| auth::sign(t, &nonce, &self.client_info.name)),
+            signed_claims: self
+                .token
+                .as_deref()
+                .and_then(auth::unsigned_part)
+                .map(str::to_string),
+        };
+        write
+            .send(Message::Text(serde_json::to_string(&auth_msg)?))
+            .await?;
+
         let register_msg = WsMessage::Register {
             client: self.client_info.clone(),
             tunnels: self.tunnel_configs.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Some(capability::supported()),
         };
         let msg_text = serde_json::to_string(&register_msg)?;
         write.send(Message::Text(msg_text)).await?;
 
         // 创建发送通道
-        let (tx, mut rx) = mpsc::unbounded_channel::<WsMessage>();
+        let (tx, mut rx) = queue::channel();
+
+        // 注册成功后按协商的能力切换帧格式
+        let codec = Arc::new(Codec::default());
+        let mut options = StreamOptions::default();
 
-        // 发送任务 — Data 用 Binary 帧，其他用 Text/JSON
-        let send_task = tokio::spawn(async move {
+        // 发送任务 — 协商了 stream_frames 时 Data 用 Binary 帧，其他用 Text/JSON
+        let send_codec = Arc::clone(&codec);
+        let mut send_task = tokio::spawn(async move {
             while let Some(msg) = rx.recv().await {
-                let ws_msg = match &msg {
-                    WsMessage::Data { conn_id, data } => {
-                        // Binary 帧: conn_id(36 bytes) + payload
-                        let mut buf = Vec::with_capacity(36 + data.len());
-                        let id_bytes = conn_id.as_bytes();
-                        if id_bytes.len() >= 36 {
-                            buf.extend_from_slice(&id_bytes[..36]);
-                        } else {
-                            buf.extend_from_slice(id_bytes);
-                            buf.resize(36, 0);
-                        }
-                        buf.extend_from_slice(data);
-                        Message::Binary(buf)
-                    }
-                    _ => {
-                        match serde_json::to_string(&msg) {
-                            Ok(t) => Message::Text(t),
-                            Err(_) => continue,
-                        }
-                    }
+                let ws_msg = match send_codec.encode(&msg) {
+                    Some(Encoded::Text(t)) => Message::Text(t),
+                    Some(Encoded::Binary(b)) => Message::Binary(b),
+                    None => continue,
                 };
                 if write.send(ws_msg).await.is_err() {
                     break;
@@ -130,8 +365,18 @@ impl TunnelClient {
             }
         });
 
-        // 接收消息
-        while let Some(msg) = read.next().await {
+        // 接收消息，发送任务退出 (连接断开或发送队列已满) 时结束会话
+        loop {
+            let msg = tokio::select! {
+                msg = read.next() => msg,
+                _ = &mut send_task => {
+                    warn!("发送任务已退出，断开连接");
+                    break;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
             match msg {
                 Ok(Message::Text(text)) => {
                     let ws_msg: WsMessage = match serde_json::from_str(&text) {
@@ -148,40 +393,94 @@ impl TunnelClient {
                             client_id,
                             tunnels,
                             message,
+                            protocol_version,
+                            capabilities,
                         } => {
                             if success {
                                 info!("注册成功，客户端 ID: {}", client_id);
+                                let capabilities = capability::negotiate(capabilities.as_deref());
+                                debug!(
+                                    "协议版本 {}，能力: {}",
+                                    protocol_version,
+                                    capabilities.join(", ")
+                                );
+                                codec.negotiated(&capabilities);
+                                options = StreamOptions::negotiated(&capabilities);
                                 for tunnel in &tunnels {
-                                    info!(
-                                        "  隧道 {} -> {}:{} (服务端端口: {})",
-                                        tunnel.name,
-                                        tunnel.local_addr,
-                                        tunnel.local_port,
-                                        tunnel.server_port
-                                    );
+                                    if tunnel.secret {
+                                        info!(
+                                            "  隧道 {} -> {}:{} (私密隧道)",
+                                            tunnel.name, tunnel.local_addr, tunnel.local_port
+                                        );
+                                    } else {
+                                        info!(
+                                            "  隧道 {} -> {}:{} (服务端端口: {})",
+                                            tunnel.name,
+                                            tunnel.local_addr,
+                                            tunnel.local_port,
+                                            tunnel.server_port
+                                        );
+                                    }
                                     let mut t = self.tunnels.write().await;
                                     t.insert(tunnel.id.clone(), tunnel.clone());
                                 }
+                                *self.session.write().await = Some(Session {
+                                    tx: tx.clone(),
+                                    nonce: nonce.clone(),
+                                    options,
+                                });
                             } else {
                                 error!("注册失败: {:?}", message);
                                 return Err(anyhow::anyhow!("注册失败"));
                             }
                         }
-                        WsMessage::NewConnection { tunnel_id, conn_id } => {
+                        WsMessage::NewConnection {
+                            tunnel_id,
+                            conn_id,
+                            visitor,
+                        } => {
                             debug!("新连接 {} (隧道 {})", conn_id, tunnel_id);
-                            self.handle_new_connection(&tunnel_id, &conn_id, tx.clone())
-                                .await;
+                            self.handle_new_connection(
+                                &tunnel_id,
+                                conn_id,
+                                visitor,
+                                tx.clone(),
+                                options,
+                            )
+                            .await;
                         }
-                        WsMessage::Data { conn_id, data } => {
-                            self.handle_data(&conn_id, data).await;
+                        msg @ (WsMessage::Data { .. }
+                        | WsMessage::WindowUpdate { .. }
+                        | WsMessage::HalfClose { .. }) => {
+                            self.handle_stream_frame(msg, &tx).await;
                         }
-                        WsMessage::CloseConnection { conn_id } => {
-                            self.handle_close(&conn_id).await;
+                        WsMessage::CloseConnection { conn_id, reason } => {
+                            self.handle_close(conn_id, reason).await;
+                        }
+                        WsMessage::VisitorAccept {
+                            request_id,
+                            conn_id,
+                            success,
+                            message,
+                            compress,
+                        } => {
+                            let accepted = if success {
+                                Ok((conn_id, compress))
+                            } else {
+                                Err(message.unwrap_or_default())
+                            };
+                            self.handle_visitor_accept(&request_id, conn_id, accepted, &tx)
+                                .await;
                         }
                         WsMessage::Pong { .. } => {
                             debug!("收到 Pong");
                         }
                         WsMessage::Error { code, message } => {
+                            if code.is_fatal() {
+                                send_task.abort();
+                                ping_task.abort();
+                                return Err(FatalError(message).into());
+                            }
                             error!("服务器错误 {}: {}", code, message);
                         }
                         WsMessage::AddTunnel { request_id, tunnel: config } => {
@@ -189,6 +488,25 @@ impl TunnelClient {
                                 "服务端下发隧道: {}:{} -> 服务端端口 {:?}",
                                 config.local_addr, config.local_port, config.remote_port
                             );
+                            let checked = if config.e2e && self.e2e_secret.is_none() {
+                                Err("隧道要求 e2e 加密，但客户端未配置 --e2e-secret".to_string())
+                            } else if config.secret && self.secret.is_none() {
+                                Err("私密隧道需要密钥，但客户端未配置 --secret-file".to_string())
+                            } else {
+                                self.policy
+                                    .check(&config.local_addr, config.local_port)
+                                    .await
+                            };
+                            if let Err(reason) = checked {
+                                warn!("拒绝服务端下发的隧道: {}", reason);
+                                let _ = tx.send(WsMessage::AddTunnelResponse {
+                                    request_id,
+                                    success: false,
+                                    tunnel: None,
+                                    message: Some(reason),
+                                });
+                                continue;
+                            }
                             // 服务端已创建隧道，客户端只需记录本地映射
                             let tunnel_info = TunnelInfo {
                                 id: request_id.clone(),
@@ -203,6 +521,11 @@ impl TunnelClient {
                                 bytes_recv: 0,
                                 created_at: String::new(),
                                 last_active_at: String::new(),
+                                allow: config.allow.clone(),
+                                deny: config.deny.clone(),
+                                e2e: config.e2e,
+                                secret: config.secret,
+                                compress: config.compress,
                             };
                             let mut t = self.tunnels.write().await;
                             t.insert(tunnel_info.id.clone(), tunnel_info.clone());
@@ -211,6 +534,12 @@ impl TunnelClient {
                                 tunnel_info.name, tunnel_info.local_addr,
                                 tunnel_info.local_port, tunnel_info.server_port
                             );
+                            let _ = tx.send(WsMessage::AddTunnelResponse {
+                                request_id,
+                                success: true,
+                                tunnel: Some(tunnel_info),
+                                message: None,
+                            });
                         }
                         WsMessage::AddTunnelResponse { request_id, success, tunnel, .. } => {
                             // 服务端确认隧道已创建，更新本地 tunnel 映射
@@ -231,11 +560,8 @@ impl TunnelClient {
                     }
                 }
                 Ok(Message::Binary(data)) => {
-                    // Binary 帧: conn_id(36 bytes) + payload
-                    if data.len() > 36 {
-                        let conn_id = String::from_utf8_lossy(&data[..36]).to_string();
-                        let payload = data[36..].to_vec();
-                        self.handle_data(&conn_id, payload).await;
+                    for msg in codec.decode_binary(&data) {
+                        self.handle_stream_frame(msg, &tx).await;
                     }
                 }
                 Ok(Message::Close(_)) => {
@@ -259,8 +585,10 @@ impl TunnelClient {
     async fn handle_new_connection(
         &self,
         tunnel_id: &str,
-        conn_id: &str,
-        tx: mpsc::UnboundedSender<WsMessage>,
+        conn_id: StreamId,
+        visitor: Option<VisitorAuth>,
+        tx: SendQueue,
+        options: StreamOptions,
     ) {
         let tunnels = self.tunnels.read().await;
         let tunnel = match tunnels.get(tunnel_id) {
@@ -272,101 +600,391 @@ impl TunnelClient {
         };
         drop(tunnels);
 
+        // 私密隧道由本端以密钥校验访问端，密钥不经过服务端
+        if tunnel.secret {
+            let verified = match (&visitor, self.secret_key(&tunnel)) {
+                (Some(v), Some(key)) => auth::verify(key, &v.nonce, &v.request_id, &v.mac),
+                _ => false,
+            };
+            if !verified {
+                warn!("私密隧道 {} 的访问端密钥错误", tunnel.name);
+                let _ = tx.send(options.failed(
+                    conn_id,
+                    CloseReason::AuthFailed,
+                    "密钥错误".to_string(),
+                ));
+                return;
+            }
+        }
+
+        // 本地 -t 配置为 e2e 的目标以本地配置为准，服务端无法将其降级为明文
+        let e2e = tunnel.e2e
+            || self.tunnel_configs.iter().any(|c| {
+                c.e2e && c.local_addr == tunnel.local_addr && c.local_port == tunnel.local_port
+            });
+        let e2e_secret = match (e2e, &self.e2e_secret) {
+            (false, _) => None,
+            (true, Some(secret)) => Some(Arc::clone(secret)),
+            (true, None) => {
+                warn!(
+                    "隧道 {} 要求 e2e 加密，但客户端未配置 --e2e-secret",
+                    tunnel_id
+                );
+                let _ = tx.send(options.failed(
+                    conn_id,
+                    CloseReason::Denied,
+                    "客户端未配置 --e2e-secret".to_string(),
+                ));
+                return;
+            }
+        };
+
         let local_addr = format!("{}:{}", tunnel.local_addr, tunnel.local_port);
-        let conn_id = conn_id.to_string();
         let tunnel_id = tunnel_id.to_string();
         let connections = Arc::clone(&self.connections);
+        let policy = Arc::clone(&self.policy);
 
         tokio::spawn(async move {
+            // 按 --allow-target 策略解析目标，只连接允许的地址
+            let addrs = match policy.resolve(&tunnel.local_addr, tunnel.local_port).await {
+                Ok(addrs) => addrs,
+                Err(reason) => {
+                    warn!("拒绝连接本地服务: {}", reason);
+                    let _ = tx.send(options.failed(conn_id, CloseReason::Denied, reason));
+                    return;
+                }
+            };
+
             // 连接本地服务
-            let stream = match TcpStream::connect(&local_addr).await {
+            let stream = match TcpStream::connect(&addrs[..]).await {
                 Ok(s) => s,
                 Err(e) => {
                     error!("连接本地服务 {} 失败: {}", local_addr, e);
-                    let _ = tx.send(WsMessage::CloseConnection {
-                        conn_id: conn_id.clone(),
-                    });
+                    let failed = options.failed(conn_id, CloseReason::ConnectFailed, e.to_string());
+                    let _ = tx.send(failed);
                     return;
                 }
             };
 
             debug!("已连接本地服务 {}", local_addr);
 
-            // 通知服务端连接就绪
+            // 先创建数据通道再通知就绪，服务端收到 ConnectionReady 后才开始转发数据
+            let (conn, data_rx) = LocalConn::new(options.flow_control);
+            let window = Arc::clone(&conn.window);
+            {
+                let mut conns = connections.write().await;
+                conns.insert(conn_id, conn);
+            }
             let _ = tx.send(WsMessage::ConnectionReady {
                 tunnel_id: tunnel_id.clone(),
-                conn_id: conn_id.clone(),
+                conn_id,
             });
 
-            // 创建数据通道
-            let (data_tx, mut data_rx) = mpsc::unbounded_channel::<Vec<u8>>();
-            {
-                let mut conns = connections.write().await;
-                conns.insert(conn_id.clone(), data_tx);
-            }
+            let (read_half, write_half) = wrap_e2e(stream, e2e_secret, e2e::Role::Client, conn_id);
+            let compress = tunnel.compress.filter(|_| options.deflate);
+            relay(
+                read_half,
+                write_half,
+                (conn_id, data_rx, window),
+                (options.half_close, compress),
+                tx,
+                connections,
+            )
+            .await;
+        });
+    }
 
-            let (mut read_half, mut write_half) = stream.into_split();
-            let conn_id_clone = conn_id.clone();
-            let tx_clone = tx.clone();
-
-            // 从本地服务读取，发送到服务端
-            let read_task = tokio::spawn(async move {
-                let mut buf = [0u8; 8192];
-                loop {
-                    match read_half.read(&mut buf).await {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            let data = buf[..n].to_vec();
-                            if tx_clone
-                                .send(WsMessage::Data {
-                                    conn_id: conn_id_clone.clone(),
-                                    data,
-                                })
-                                .is_err()
-                            {
-                                break;
-                            }
-                        }
-                        Err(_) => break,
-                    }
+    /// 监听本地端口，每个连接经服务端转发到目标隧道
+    async fn serve_visitor(&self, config: &VisitorConfig) -> Result<()> {
+        let listener = TcpListener::bind(&config.listen)
+            .await
+            .with_context(|| format!("监听 {} 失败", config.listen))?;
+        info!(
+            "访问端: {} -> {}/{}{}",
+            listener.local_addr()?,
+            config.client_name,
+            config.tunnel_name,
+            if config.e2e { " (e2e)" } else { "" }
+        );
+
+        let config = Arc::new(config.clone());
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // EMFILE、ECONNABORTED 等错误是暂时的，不能让整个进程退出；
+                    // 稍等再试，避免文件描述符耗尽时空转
+                    error!("访问端 {} Accept 错误: {}", config.listen, e);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                    continue;
                 }
-            });
+            };
+            let Some(session) = self.session.read().await.clone() else {
+                warn!("尚未连接到服务端，关闭本地连接 {}", addr);
+                continue;
+            };
+            debug!("访问端新连接 {}", addr);
+            let e2e_secret = self.e2e_secret.clone().filter(|_| config.e2e);
+            tokio::spawn(visit(
+                stream,
+                session,
+                Arc::clone(&config),
+                e2e_secret,
+                Arc::clone(&self.connections),
+                Arc::clone(&self.pending_visitors),
+            ));
+        }
+    }
 
-            // 从服务端接收，写入本地服务
-            let write_task = tokio::spawn(async move {
-                while let Some(data) = data_rx.recv().await {
-                    if write_half.write_all(&data).await.is_err() {
-                        break;
+    /// 连接数据、流量控制和半关闭消息，JSON 和二进制帧共用
+    async fn handle_stream_frame(&self, msg: WsMessage, tx: &SendQueue) {
+        match msg {
+            // 空数据在连接通道中表示 HalfClose
+            WsMessage::Data { data, .. } if data.is_empty() => {}
+            WsMessage::Data {
+                conn_id,
+                data,
+                compressed,
+            } => {
+                let mut conns = self.connections.write().await;
+                let Some(conn) = conns.get(&conn_id) else {
+                    return;
+                };
+                let data = if compressed {
+                    compress::decompress(&data)
+                } else {
+                    Some(data)
+                };
+                let reason = match data {
+                    Some(data) => {
+                        if conn.window.deliver(&conn.tx, data) {
+                            return;
+                        }
+                        // 服务端未遵守流量控制，关闭连接而不是无限缓存
+                        warn!("连接 {} 的数据超出窗口或缓存上限，关闭连接", conn_id);
+                        CloseReason::FlowControl
+                    }
+                    None => {
+                        warn!("连接 {} 的压缩数据无法解压，关闭连接", conn_id);
+                        CloseReason::InvalidData
                     }
+                };
+                conns.remove(&conn_id);
+                let _ = tx.send(WsMessage::CloseConnection { conn_id, reason });
+            }
+            WsMessage::WindowUpdate { conn_id, credit } => {
+                if let Some(conn) = self.connections.read().await.get(&conn_id) {
+                    conn.window.grant(credit);
                 }
-            });
-
-            tokio::select! {
-                _ = read_task => {}
-                _ = write_task => {}
             }
+            WsMessage::HalfClose { conn_id } => {
+                if let Some(conn) = self.connections.read().await.get(&conn_id) {
+                    // 通道为 HalfClose 预留了容量，重复的 HalfClose 直接丢弃
+                    let _ = conn.tx.try_send(Vec::new());
+                }
+            }
+            _ => {}
+        }
+    }
 
-            // 清理
-            {
-                let mut conns = connections.write().await;
-                conns.remove(&conn_id);
+    async fn handle_close(&self, conn_id: StreamId, reason: CloseReason) {
+        let mut conns = self.connections.write().await;
+        conns.remove(&conn_id);
+        debug!("连接 {} 已关闭: {}", conn_id, reason);
+    }
+
+    /// 访问端连接结果，成功时在处理后续消息前登记数据通道，避免对端数据先到而丢失
+    async fn handle_visitor_accept(
+        &self,
+        request_id: &str,
+        conn_id: StreamId,
+        accepted: VisitorAccepted,
+        tx: &SendQueue,
+    ) {
+        let pending = self.pending_visitors.write().await.remove(request_id);
+        match pending {
+            Some((accept, conn)) => {
+                if accepted.is_ok() {
+                    self.connections.write().await.insert(conn_id, conn);
+                }
+                let _ = accept.send(accepted);
             }
-            let _ = tx.send(WsMessage::CloseConnection { conn_id });
-        });
+            // 访问端已超时放弃，关闭服务端上的连接
+            None if accepted.is_ok() => {
+                let _ = tx.send(WsMessage::CloseConnection {
+                    conn_id,
+                    reason: CloseReason::ReadyTimeout,
+                });
+            }
+            None => {}
+        }
     }
+}
+
+/// 访问端的单个连接：请求服务端打开目标隧道，对端就绪后开始转发
+async fn visit(
+    stream: TcpStream,
+    session: Session,
+    config: Arc<VisitorConfig>,
+    e2e_secret: Option<Arc<str>>,
+    connections: Connections,
+    pending: PendingVisitors,
+) {
+    let request_id = Uuid::new_v4().to_string();
+
+    // 数据通道随请求登记，收到 VisitorAccept 时按服务端分配的流 ID 加入连接表
+    let (conn, data_rx) = LocalConn::new(session.options.flow_control);
+    let window = Arc::clone(&conn.window);
+    let (accept_tx, accept_rx) = oneshot::channel();
+    pending
+        .write()
+        .await
+        .insert(request_id.clone(), (accept_tx, conn));
+
+    let _ = session.tx.send(WsMessage::VisitorOpen {
+        request_id: request_id.clone(),
+        client_name: config.client_name.clone(),
+        tunnel_name: config.tunnel_name.clone(),
+        mac: config
+            .secret_key
+            .as_deref()
+            .map(|key| auth::sign(key, &session.nonce, &request_id)),
+    });
+
+    let accepted = match tokio::time::timeout(VISITOR_OPEN_TIMEOUT, accept_rx).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("连接已被关闭".to_string()),
+        Err(_) => Err("等待服务端响应超时".to_string()),
+    };
+    pending.write().await.remove(&request_id);
+    let (conn_id, compress) = match accepted {
+        Ok(accepted) => accepted,
+        Err(reason) => {
+            warn!(
+                "访问 {}/{} 失败: {}",
+                config.client_name, config.tunnel_name, reason
+            );
+            return;
+        }
+    };
+
+    debug!("访问端连接 {} 已就绪", conn_id);
+    let (read_half, write_half) = wrap_e2e(stream, e2e_secret, e2e::Role::Visitor, conn_id);
+    relay(
+        read_half,
+        write_half,
+        (conn_id, data_rx, window),
+        (session.options.half_close, compress),
+        session.tx,
+        connections,
+    )
+    .await;
+}
 
-    async fn handle_data(&self, conn_id: &str, data: Vec<u8>) {
-        let conns = self.connections.read().await;
-        if let Some(tx) = conns.get(conn_id) {
-            let _ = tx.send(data);
+/// e2e 连接在本地连接与服务端之间插入加解密层，转发的是密文
+fn wrap_e2e(
+    stream: TcpStream,
+    e2e_secret: Option<Arc<str>>,
+    role: e2e::Role,
+    conn_id: StreamId,
+) -> (BoxedRead, BoxedWrite) {
+    match e2e_secret {
+        Some(secret) => {
+            let (relay, cipher_side) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(async move {
+                if let Err(e) = e2e::bridge(stream, cipher_side, &secret, role).await {
+                    warn!("连接 {} E2E 错误: {:#}", conn_id, e);
+                }
+            });
+            let (r, w) = tokio::io::split(relay);
+            (Box::new(r), Box::new(w))
+        }
+        None => {
+            let (r, w) = stream.into_split();
+            (Box::new(r), Box::new(w))
         }
     }
+}
 
-    async fn handle_close(&self, conn_id: &str) {
-        let mut conns = self.connections.write().await;
-        conns.remove(conn_id);
-        debug!("连接 {} 已关闭", conn_id);
+/// 在本地连接与服务端之间双向转发
+///
+/// 协商了半关闭时一个方向结束后继续转发另一方向，否则任一方向结束即通知服务端关闭连接。
+/// 设置了压缩阈值 `compress` 时压缩发往服务端的数据。
+async fn relay(
+    mut read_half: BoxedRead,
+    mut write_half: BoxedWrite,
+    (conn_id, mut data_rx, window): (StreamId, DataReceiver, Arc<Window>),
+    (half_close, compress): (bool, Option<u32>),
+    tx: SendQueue,
+    connections: Connections,
+) {
+    let tx_clone = tx.clone();
+    let tx_update = tx.clone();
+    let window_r = Arc::clone(&window);
+
+    // 从本地连接读取，发送到服务端
+    let read_task = tokio::spawn(async move {
+        let mut buf = [0u8; 8192];
+        loop {
+            match read_half.read(&mut buf).await {
+                Ok(0) if half_close => {
+                    return match tx_clone.send(WsMessage::HalfClose { conn_id }) {
+                        Ok(()) => None,
+                        Err(_) => Some(CloseReason::Disconnected),
+                    };
+                }
+                Ok(0) => return Some(CloseReason::Closed),
+                Ok(n) => {
+                    // 等待服务端归还额度，避免对端读取慢时数据堆积在服务端
+                    if !window_r.reserve(n).await {
+                        return Some(CloseReason::PeerClosed);
+                    }
+                    let (data, compressed) =
+                        match compress.and_then(|t| compress::compress(&buf[..n], t)) {
+                            Some(data) => (data, true),
+                            None => (buf[..n].to_vec(), false),
+                        };
+                    if !tx_clone
+                        .send_data(WsMessage::Data {
+                            conn_id,
+                            data,
+                            compressed,
+                        })
+                        .await
+                    {
+                        return Some(CloseReason::Disconnected);
+                    }
+                }
+                Err(_) => return Some(CloseReason::Reset),
+            }
+        }
+    });
+
+    // 从服务端接收，写入本地连接
+    let write_task = tokio::spawn(async move {
+        while let Some(data) = data_rx.recv().await {
+            if data.is_empty() {
+                let _ = write_half.shutdown().await;
+                return Ok(data_rx);
+            }
+            if write_half.write_all(&data).await.is_err() {
+                return Err(CloseReason::Reset);
+            }
+            if let Some(credit) = window.consumed(data.len()) {
+                let _ = tx_update.send(WsMessage::WindowUpdate { conn_id, credit });
+            }
+        }
+        Err(CloseReason::PeerClosed)
+    });
+
+    let reason = half_close::join(read_task, write_task).await;
+
+    // 清理
+    {
+        let mut conns = connections.write().await;
+        conns.remove(&conn_id);
     }
+    let _ = tx.send(WsMessage::CloseConnection { conn_id, reason });
 }
 
 fn get_local_ip() -> String {
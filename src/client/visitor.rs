@@ -0,0 +1,106 @@
+//! 访问端：在本地监听并转发到隧道
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::e2e;
+
+/// 访问端转发配置 (`cec-tunnel visit`)
+#[derive(Debug, Clone)]
+pub struct VisitorConfig {
+    /// 本地监听地址
+    pub listen: String,
+    /// 提供隧道的客户端名称
+    pub client_name: String,
+    pub tunnel_name: String,
+    /// 私密隧道密钥，访问公开隧道时不需要
+    pub secret_key: Option<String>,
+    /// 与隧道客户端之间端到端加密，使用 --e2e-secret
+    pub e2e: bool,
+}
+
+impl VisitorConfig {
+    /// 目标格式: 客户端名称/隧道名称
+    pub fn new(
+        target: &str,
+        listen: String,
+        secret_key: Option<String>,
+        e2e: bool,
+    ) -> Result<Self> {
+        let (client_name, tunnel_name) = target
+            .split_once('/')
+            .filter(|(c, t)| !c.is_empty() && !t.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!("无效的访问目标 {}，格式为 客户端名称/隧道名称", target)
+            })?;
+        Ok(Self {
+            listen,
+            client_name: client_name.to_string(),
+            tunnel_name: tunnel_name.to_string(),
+            secret_key,
+            e2e,
+        })
+    }
+
+    /// 解析 -L 转发: `[绑定地址:]端口:客户端名称/隧道名称`，可追加选项 `?sk=密钥&e2e`，
+    /// 未指定 sk 时使用 `default_key`
+    pub fn parse(spec: &str, default_key: Option<&str>) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("无效的转发配置: {}", spec);
+        let (forward, options) = spec.split_once('?').unwrap_or((spec, ""));
+        let (listen, target) = forward.rsplit_once(':').ok_or_else(invalid)?;
+        // 只写端口时绑定本机回环地址，与 ssh -L 一致
+        let listen = match listen.parse::<u16>() {
+            Ok(port) => format!("127.0.0.1:{}", port),
+            Err(_) => listen.to_string(),
+        };
+
+        let mut secret_key = default_key.map(str::to_string);
+        let mut e2e = false;
+        for option in options.split('&').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                None if option == "e2e" => e2e = true,
+                Some(("sk", key)) if !key.is_empty() => secret_key = Some(key.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+        Self::new(target, listen, secret_key, e2e)
+    }
+}
+
+/// `cec-tunnel connect`：本地明文连接经 E2E 加密后转发到隧道的服务端端口
+pub async fn connect(listen: &str, remote: &str, secret: &str) -> Result<()> {
+    e2e::check_secret(secret)?;
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("监听 {} 失败", listen))?;
+    info!(
+        "E2E 访问端: {} -> {} (加密)",
+        listener.local_addr()?,
+        remote
+    );
+
+    let remote: Arc<str> = Arc::from(remote);
+    let secret: Arc<str> = Arc::from(secret);
+    loop {
+        let (local, addr) = listener.accept().await?;
+        debug!("新连接 {}", addr);
+        let remote = Arc::clone(&remote);
+        let secret = Arc::clone(&secret);
+        tokio::spawn(async move {
+            let upstream = match TcpStream::connect(&*remote).await {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("连接 {} 失败: {}", remote, e);
+                    return;
+                }
+            };
+            if let Err(e) = e2e::bridge(local, upstream, &secret, e2e::Role::Visitor).await {
+                warn!("连接 {} E2E 错误: {:#}", addr, e);
+            }
+            debug!("连接 {} 已关闭", addr);
+        });
+    }
+}
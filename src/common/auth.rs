@@ -0,0 +1,52 @@
+//! 挑战-应答认证
+//!
+//! 服务端在 WebSocket 升级后下发随机 nonce，客户端用 Token 作为密钥计算
+//! `HMAC-SHA256(nonce:client_name)` 回应，Token 本身不在网络上传输。
+//! 私密隧道的访问端同样以隧道密钥计算 `HMAC-SHA256(nonce:request_id)`，由隧道客户端校验。
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// 服务端签发的自包含签名 Token 前缀
+pub const SIGNED_TOKEN_PREFIX: &str = "cec1";
+
+/// 生成随机 nonce (base64)
+#[allow(dead_code)] // 仅服务端使用
+pub fn generate_nonce() -> String {
+    let mut buf = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .expect("系统随机数生成失败");
+    STANDARD.encode(buf)
+}
+
+/// 计算挑战应答 (base64)
+#[allow(dead_code)] // 仅客户端使用
+pub fn sign(token: &str, nonce: &str, client_name: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    let tag = hmac::sign(&key, message(nonce, client_name).as_bytes());
+    STANDARD.encode(tag.as_ref())
+}
+
+/// 校验挑战应答（常量时间比较）
+pub fn verify(token: &str, nonce: &str, client_name: &str, mac: &str) -> bool {
+    let Ok(tag) = STANDARD.decode(mac) else {
+        return false;
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    hmac::verify(&key, message(nonce, client_name).as_bytes(), &tag).is_ok()
+}
+
+fn message(nonce: &str, client_name: &str) -> String {
+    format!("{}:{}", nonce, client_name)
+}
+
+/// 签名 Token 去掉签名后的部分 (`cec1.<claims>`)，普通 Token 返回 None
+#[allow(dead_code)] // 仅客户端使用
+pub fn unsigned_part(token: &str) -> Option<&str> {
+    token
+        .strip_prefix(SIGNED_TOKEN_PREFIX)
+        .filter(|rest| rest.starts_with('.'))?;
+    token.rsplit_once('.').map(|(unsigned, _)| unsigned)
+}
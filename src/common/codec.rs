@@ -0,0 +1,263 @@
+//! WebSocket 消息编解码
+//!
+//! 控制消息始终为 JSON Text。Data、WindowUpdate 和 HalfClose 在协商了 `stream_frames` 的会话中编码为
+//! 紧凑二进制帧 (见 [`frame`])，否则也以 JSON 发送。旧版客户端发送的 Binary 消息为
+//! `conn_id (36 字节，不足补 0) || 数据`，服务端仍可解析。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::common::protocol::frame::{FrameType, FLAG_COMPRESSED, HEADER_LEN};
+use crate::common::protocol::{capability, StreamId, WsMessage};
+
+/// 旧版 Binary 消息的 conn_id 前缀长度
+const LEGACY_ID_LEN: usize = 36;
+
+/// 编码后的 WebSocket 消息，由调用方转换为各自 WebSocket 库的消息类型
+pub enum Encoded {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// 单个会话的编解码器，注册后按协商的能力切换帧格式
+#[derive(Debug, Default)]
+pub struct Codec {
+    stream_frames: AtomicBool,
+}
+
+impl Codec {
+    /// 按协商的能力选择帧格式
+    pub fn negotiated(&self, capabilities: &[String]) {
+        let enabled = capabilities.iter().any(|c| c == capability::STREAM_FRAMES);
+        self.stream_frames.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn encode(&self, msg: &WsMessage) -> Option<Encoded> {
+        match msg {
+            WsMessage::Data {
+                conn_id,
+                data,
+                compressed,
+            } if self.stream_frames.load(Ordering::Relaxed) => {
+                let mut buf = Vec::with_capacity(HEADER_LEN + data.len());
+                let flags = if *compressed { FLAG_COMPRESSED } else { 0 };
+                put_frame(&mut buf, FrameType::Data, flags, *conn_id, data);
+                Some(Encoded::Binary(buf))
+            }
+            WsMessage::WindowUpdate { conn_id, credit }
+                if self.stream_frames.load(Ordering::Relaxed) =>
+            {
+                let mut buf = Vec::with_capacity(HEADER_LEN + 4);
+                let credit = credit.to_be_bytes();
+                put_frame(&mut buf, FrameType::WindowUpdate, 0, *conn_id, &credit);
+                Some(Encoded::Binary(buf))
+            }
+            WsMessage::HalfClose { conn_id } if self.stream_frames.load(Ordering::Relaxed) => {
+                let mut buf = Vec::with_capacity(HEADER_LEN);
+                put_frame(&mut buf, FrameType::HalfClose, 0, *conn_id, &[]);
+                Some(Encoded::Binary(buf))
+            }
+            _ => serde_json::to_string(msg).ok().map(Encoded::Text),
+        }
+    }
+
+    /// 解析 Binary 消息，格式错误的部分被丢弃
+    pub fn decode_binary(&self, buf: &[u8]) -> Vec<WsMessage> {
+        if self.stream_frames.load(Ordering::Relaxed) {
+            decode_frames(buf)
+        } else {
+            decode_legacy(buf).into_iter().collect()
+        }
+    }
+}
+
+fn put_frame(buf: &mut Vec<u8>, frame_type: FrameType, flags: u8, stream: StreamId, data: &[u8]) {
+    buf.push(frame_type as u8);
+    buf.push(flags);
+    buf.extend_from_slice(&stream.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn decode_frames(mut buf: &[u8]) -> Vec<WsMessage> {
+    let mut messages = Vec::new();
+    while buf.len() >= HEADER_LEN {
+        let (header, rest) = buf.split_at(HEADER_LEN);
+        let stream = StreamId::from_be_bytes([header[2], header[3], header[4], header[5]]);
+        let len = u32::from_be_bytes([header[6], header[7], header[8], header[9]]) as usize;
+        if rest.len() < len {
+            break;
+        }
+        let (payload, rest) = rest.split_at(len);
+        // 未知帧类型留给后续版本，跳过
+        match FrameType::try_from(header[0]) {
+            Ok(FrameType::Data) => messages.push(WsMessage::Data {
+                conn_id: stream,
+                data: payload.to_vec(),
+                compressed: header[1] & FLAG_COMPRESSED != 0,
+            }),
+            Ok(FrameType::WindowUpdate) => {
+                if let Ok(credit) = <[u8; 4]>::try_from(payload) {
+                    messages.push(WsMessage::WindowUpdate {
+                        conn_id: stream,
+                        credit: u32::from_be_bytes(credit),
+                    });
+                }
+            }
+            Ok(FrameType::HalfClose) => messages.push(WsMessage::HalfClose { conn_id: stream }),
+            Err(_) => {}
+        }
+        buf = rest;
+    }
+    messages
+}
+
+fn decode_legacy(buf: &[u8]) -> Option<WsMessage> {
+    if buf.len() <= LEGACY_ID_LEN {
+        return None;
+    }
+    let (id, payload) = buf.split_at(LEGACY_ID_LEN);
+    let end = id.iter().position(|b| *b == 0).unwrap_or(LEGACY_ID_LEN);
+    let conn_id = std::str::from_utf8(&id[..end]).ok()?.parse().ok()?;
+    Some(WsMessage::Data {
+        conn_id,
+        data: payload.to_vec(),
+        compressed: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiated() -> Codec {
+        let codec = Codec::default();
+        codec.negotiated(&[capability::STREAM_FRAMES.to_string()]);
+        codec
+    }
+
+    fn frame(frame_type: u8, stream: StreamId, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![frame_type, 0];
+        buf.extend_from_slice(&stream.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn binary(codec: &Codec, msg: &WsMessage) -> Vec<u8> {
+        match codec.encode(msg) {
+            Some(Encoded::Binary(buf)) => buf,
+            _ => panic!("应编码为 Binary"),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let codec = negotiated();
+        let mut buf = binary(
+            &codec,
+            &WsMessage::Data {
+                conn_id: 7,
+                data: b"hello".to_vec(),
+                compressed: true,
+            },
+        );
+        buf.extend(binary(
+            &codec,
+            &WsMessage::WindowUpdate {
+                conn_id: 7,
+                credit: 65536,
+            },
+        ));
+        buf.extend(binary(&codec, &WsMessage::HalfClose { conn_id: 7 }));
+
+        let messages = codec.decode_binary(&buf);
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(
+            &messages[0],
+            WsMessage::Data { conn_id: 7, data, compressed: true } if data == b"hello"
+        ));
+        assert!(matches!(
+            messages[1],
+            WsMessage::WindowUpdate {
+                conn_id: 7,
+                credit: 65536
+            }
+        ));
+        assert!(matches!(messages[2], WsMessage::HalfClose { conn_id: 7 }));
+    }
+
+    #[test]
+    fn json_before_negotiation() {
+        let codec = Codec::default();
+        let msg = WsMessage::Data {
+            conn_id: 1,
+            data: b"x".to_vec(),
+            compressed: false,
+        };
+        assert!(matches!(codec.encode(&msg), Some(Encoded::Text(_))));
+    }
+
+    #[test]
+    fn truncated_frames() {
+        let codec = negotiated();
+        // 帧头不完整
+        assert!(codec.decode_binary(&[0, 0, 0, 0, 0, 1]).is_empty());
+        // 数据不完整，之前的完整帧保留
+        let mut buf = frame(0, 1, b"ok");
+        let mut partial = frame(0, 2, b"truncated");
+        partial.truncate(partial.len() - 3);
+        buf.extend(partial);
+        let messages = codec.decode_binary(&buf);
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(&messages[0], WsMessage::Data { conn_id: 1, data, .. } if data == b"ok"));
+    }
+
+    #[test]
+    fn unknown_frame_type_skipped() {
+        let codec = negotiated();
+        let mut buf = frame(0xff, 1, b"future");
+        buf.extend(frame(0, 2, b"data"));
+        let messages = codec.decode_binary(&buf);
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(messages[0], WsMessage::Data { conn_id: 2, .. }));
+    }
+
+    #[test]
+    fn window_update_wrong_length_skipped() {
+        let codec = negotiated();
+        let mut buf = frame(FrameType::WindowUpdate as u8, 1, &[0, 1, 0]);
+        buf.extend(frame(FrameType::WindowUpdate as u8, 1, &[0, 0, 1, 0, 0]));
+        buf.extend(frame(FrameType::WindowUpdate as u8, 1, &[0, 0, 1, 0]));
+        let messages = codec.decode_binary(&buf);
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            messages[0],
+            WsMessage::WindowUpdate {
+                conn_id: 1,
+                credit: 256
+            }
+        ));
+    }
+
+    #[test]
+    fn legacy_prefix() {
+        let codec = Codec::default();
+        let mut buf = b"42".to_vec();
+        buf.resize(LEGACY_ID_LEN, 0);
+        buf.extend_from_slice(b"payload");
+        let messages = codec.decode_binary(&buf);
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            WsMessage::Data { conn_id: 42, data, compressed: false } if data == b"payload"
+        ));
+
+        // 只有前缀没有数据
+        assert!(codec.decode_binary(&buf[..LEGACY_ID_LEN]).is_empty());
+        // 前缀不是数字
+        let mut bad = b"not-a-number".to_vec();
+        bad.resize(LEGACY_ID_LEN, 0);
+        bad.push(1);
+        assert!(codec.decode_binary(&bad).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+//! 连接数据压缩
+//!
+//! 隧道配置了压缩阈值 (`?compress`) 且会话协商了 `deflate` 时，发送方把不小于阈值的 Data
+//! 用 deflate 压缩后发送，并在帧标志中标记 (见 [`FLAG_COMPRESSED`])；压缩后没有变小的数据
+//! 原样发送。接收方解压后再写入本地连接，流量控制按解压后的长度计算。
+//!
+//! [`FLAG_COMPRESSED`]: crate::common::protocol::frame::FLAG_COMPRESSED
+
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+/// `?compress` 未指定阈值时的默认值 (字节)
+pub const DEFAULT_THRESHOLD: u32 = 256;
+
+/// 解压后的最大长度，超出视为无效数据，避免压缩炸弹
+const MAX_DECOMPRESSED: u64 = 1024 * 1024;
+
+/// 压缩达到阈值的数据，未达到阈值或压缩后没有变小时返回 None
+pub fn compress(data: &[u8], threshold: u32) -> Option<Vec<u8>> {
+    if data.len() < threshold as usize {
+        return None;
+    }
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len()), Compression::fast());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// 解压数据，格式错误、解压结果为空或超出长度限制时返回 None
+///
+/// 空数据在连接通道中表示 HalfClose，不能由 Data 产生。
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = DeflateDecoder::new(data).take(MAX_DECOMPRESSED + 1);
+    let mut buf = Vec::with_capacity(data.len() * 4);
+    decoder.read_to_end(&mut buf).ok()?;
+    (!buf.is_empty() && buf.len() as u64 <= MAX_DECOMPRESSED).then_some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"GET /api/items HTTP/1.1\r\nHost: example\r\n\r\n".repeat(20);
+        let compressed = compress(&data, DEFAULT_THRESHOLD).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn below_threshold_not_compressed() {
+        let data = vec![b'a'; 100];
+        assert!(compress(&data, 101).is_none());
+        assert!(compress(&data, 100).is_some());
+    }
+
+    #[test]
+    fn incompressible_not_compressed() {
+        // 简单的伪随机数据
+        let mut x: u32 = 12345;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        assert!(compress(&data, 0).is_none());
+    }
+
+    #[test]
+    fn decompress_limit() {
+        let max = MAX_DECOMPRESSED as usize;
+        let exact = compress(&vec![0u8; max], 0).unwrap();
+        assert_eq!(decompress(&exact).map(|d| d.len()), Some(max));
+        let bomb = compress(&vec![0u8; max + 1], 0).unwrap();
+        assert!(decompress(&bomb).is_none());
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        assert!(decompress(b"\xff\xff\xff\xff not deflate").is_none());
+    }
+}
@@ -0,0 +1,183 @@
+//! 按连接的流量控制
+//!
+//! 每个连接的每个方向各有一个窗口：发送方最多有 [`INITIAL_WINDOW`] 字节未被对端确认，
+//! 额度用完后暂停读取本地连接；接收方把数据写入本地连接后通过 WindowUpdate 归还额度。
+//! 对端不支持流量控制 (未协商 `flow_control`) 时不限制发送，但接收方最多缓存
+//! [`UNCONTROLLED_LIMIT`] 字节，超出后关闭连接。
+//!
+//! 收到的数据经有界通道 ([`data_channel`]) 交给写入本地连接的任务，通道满时同样关闭连接。
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Semaphore;
+
+/// 每个连接每个方向的窗口大小 (字节)
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// 已消费的字节累计达到该值后归还额度，避免每个 Data 都回复 WindowUpdate
+const UPDATE_THRESHOLD: u32 = INITIAL_WINDOW / 4;
+
+/// 对端不支持流量控制时最多缓存的字节数，超出后关闭连接
+pub const UNCONTROLLED_LIMIT: u32 = 4 * 1024 * 1024;
+
+/// 创建连接的数据通道，容量为窗口大小 (条) 外加一条 HalfClose
+///
+/// 每条 Data 至少 1 字节，遵守流量控制的对端不会填满通道。
+pub fn data_channel() -> (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+    mpsc::channel(INITIAL_WINDOW as usize + 1)
+}
+
+/// 单个连接的窗口
+#[derive(Debug)]
+pub struct Window {
+    /// 发送额度 (字节)，None 表示不做流量控制
+    send: Option<Semaphore>,
+    /// 已收到但尚未写入本地连接的字节数
+    buffered: AtomicU32,
+    /// 已写入本地连接、尚未归还给对端的字节数
+    unacked: AtomicU32,
+}
+
+impl Window {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            send: enabled.then(|| Semaphore::new(INITIAL_WINDOW as usize)),
+            buffered: AtomicU32::new(0),
+            unacked: AtomicU32::new(0),
+        }
+    }
+
+    /// 发送 `n` 字节前等待对端额度，连接已关闭时返回 false
+    pub async fn reserve(&self, n: usize) -> bool {
+        let Some(send) = &self.send else {
+            return true;
+        };
+        match send.acquire_many(n as u32).await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// 连接关闭，唤醒等待额度的发送方
+    pub fn close(&self) {
+        if let Some(send) = &self.send {
+            send.close();
+        }
+    }
+
+    /// 对端归还额度
+    pub fn grant(&self, credit: u32) {
+        if let Some(send) = &self.send {
+            // 额度不会超过窗口大小，忽略异常的 WindowUpdate
+            let room = (INITIAL_WINDOW as usize).saturating_sub(send.available_permits());
+            send.add_permits((credit as usize).min(room));
+        }
+    }
+
+    /// 收到 `n` 字节，超出窗口 (对端未遵守流量控制) 或缓存上限时返回 false
+    pub fn received(&self, n: usize) -> bool {
+        let limit = match self.send {
+            Some(_) => INITIAL_WINDOW,
+            None => UNCONTROLLED_LIMIT,
+        };
+        let buffered = self.buffered.fetch_add(n as u32, Ordering::Relaxed) + n as u32;
+        buffered <= limit
+    }
+
+    /// 把收到的数据交给写入任务，超出窗口、缓存上限或通道已满时返回 false
+    ///
+    /// 写入任务已退出时丢弃数据，连接随后会被关闭。
+    pub fn deliver(&self, tx: &mpsc::Sender<Vec<u8>>, data: Vec<u8>) -> bool {
+        if !self.received(data.len()) {
+            return false;
+        }
+        !matches!(tx.try_send(data), Err(TrySendError::Full(_)))
+    }
+
+    /// `n` 字节已写入本地连接，返回需要通过 WindowUpdate 归还的额度
+    pub fn consumed(&self, n: usize) -> Option<u32> {
+        self.buffered.fetch_sub(n as u32, Ordering::Relaxed);
+        self.send.as_ref()?;
+        let unacked = self.unacked.fetch_add(n as u32, Ordering::Relaxed) + n as u32;
+        if unacked < UPDATE_THRESHOLD {
+            return None;
+        }
+        Some(self.unacked.swap(0, Ordering::Relaxed)).filter(|c| *c > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn disabled_window_does_not_limit_sender() {
+        let window = Window::new(false);
+        assert!(window.reserve(INITIAL_WINDOW as usize * 4).await);
+        assert!(window.received(INITIAL_WINDOW as usize * 4));
+        assert_eq!(window.consumed(INITIAL_WINDOW as usize), None);
+    }
+
+    #[test]
+    fn disabled_window_caps_buffering() {
+        let window = Window::new(false);
+        assert!(window.received(UNCONTROLLED_LIMIT as usize));
+        assert_eq!(window.consumed(1024), None);
+        assert!(window.received(1024));
+        assert!(!window.received(1));
+    }
+
+    #[tokio::test]
+    async fn grant_clamped_to_window() {
+        let window = Window::new(true);
+        assert!(window.reserve(1024).await);
+        window.grant(INITIAL_WINDOW * 2);
+        let send = window.send.as_ref().unwrap();
+        assert_eq!(send.available_permits(), INITIAL_WINDOW as usize);
+    }
+
+    #[tokio::test]
+    async fn reserve_fails_after_close() {
+        let window = Window::new(true);
+        assert!(window.reserve(INITIAL_WINDOW as usize).await);
+        window.close();
+        assert!(!window.reserve(1).await);
+    }
+
+    #[test]
+    fn received_overflow() {
+        let window = Window::new(true);
+        assert!(window.received(INITIAL_WINDOW as usize));
+        assert!(!window.received(1));
+    }
+
+    #[test]
+    fn deliver_rejects_full_channel() {
+        let window = Window::new(false);
+        let (tx, mut rx) = mpsc::channel(1);
+        assert!(window.deliver(&tx, vec![1]));
+        assert!(!window.deliver(&tx, vec![2]));
+        assert_eq!(rx.try_recv().unwrap(), vec![1]);
+        // 写入任务退出后不再报告溢出
+        drop(rx);
+        assert!(window.deliver(&tx, vec![3]));
+    }
+
+    #[test]
+    fn consumed_returns_credit_at_threshold() {
+        let window = Window::new(true);
+        let n = UPDATE_THRESHOLD as usize;
+        assert!(window.received(n));
+        assert_eq!(window.consumed(n - 1), None);
+        assert_eq!(window.consumed(1), Some(UPDATE_THRESHOLD));
+        // 归还后重新累计
+        assert!(window.received(n));
+        assert_eq!(window.consumed(1), None);
+        // 已消费的数据不再计入窗口
+        assert!(window.received(INITIAL_WINDOW as usize - n + 1));
+    }
+}
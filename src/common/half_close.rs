@@ -0,0 +1,97 @@
+//! 连接两个方向的收尾 (TCP 半关闭)
+//!
+//! 连接的数据通道中空数据表示对端已关闭写方向 (HalfClose)。协商了 `half_close` 时一个方向
+//! 正常结束 (本地读到 EOF 或收到对端 HalfClose) 后继续转发另一方向，两个方向都结束、
+//! 任一方向出错或连接被移除时才关闭整个连接。
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::common::protocol::CloseReason;
+
+pub type DataReceiver = mpsc::Receiver<Vec<u8>>;
+
+/// 等待连接的两个方向结束，返回关闭原因
+///
+/// `read` 返回 None 表示本地连接关闭了写方向且已通知对端；`write` 收到对端 HalfClose 并关闭
+/// 本地写方向后交回数据通道，通道关闭 (连接被移除) 时结束等待。返回前结束仍在运行的任务。
+pub async fn join(
+    mut read: JoinHandle<Option<CloseReason>>,
+    mut write: JoinHandle<Result<DataReceiver, CloseReason>>,
+) -> CloseReason {
+    let reason = tokio::select! {
+        r = &mut read => match r {
+            Ok(None) => match (&mut write).await {
+                Ok(Ok(_)) => CloseReason::Closed,
+                Ok(Err(reason)) => reason,
+                Err(_) => CloseReason::Unknown,
+            },
+            Ok(Some(reason)) => reason,
+            Err(_) => CloseReason::Unknown,
+        },
+        w = &mut write => match w {
+            Ok(Ok(mut data_rx)) => {
+                // 对端不会再发来数据，收到数据或通道关闭都结束
+                tokio::select! {
+                    r = &mut read => r.unwrap_or_default().unwrap_or(CloseReason::Closed),
+                    _ = data_rx.recv() => CloseReason::PeerClosed,
+                }
+            }
+            Ok(Err(reason)) => reason,
+            Err(_) => CloseReason::Unknown,
+        },
+    };
+    // 另一方向可能还阻塞在本地连接的读写上，结束它以释放连接
+    read.abort();
+    write.abort();
+    reason
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// 永不结束的任务，持有 `alive` 的引用直到被结束
+    fn pending<T: Send + 'static>(alive: &Arc<()>) -> JoinHandle<T> {
+        let alive = Arc::clone(alive);
+        tokio::spawn(async move {
+            let _alive = alive;
+            std::future::pending().await
+        })
+    }
+
+    #[tokio::test]
+    async fn write_error_aborts_read() {
+        let alive = Arc::new(());
+        let read = pending(&alive);
+        let write = tokio::spawn(async { Err(CloseReason::Reset) });
+        assert_eq!(join(read, write).await, CloseReason::Reset);
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&alive), 1);
+    }
+
+    #[tokio::test]
+    async fn read_error_aborts_write() {
+        let alive = Arc::new(());
+        let read = tokio::spawn(async { Some(CloseReason::Reset) });
+        let write = pending(&alive);
+        assert_eq!(join(read, write).await, CloseReason::Reset);
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&alive), 1);
+    }
+
+    #[tokio::test]
+    async fn remote_half_close_waits_for_read() {
+        let (tx, rx) = mpsc::channel(1);
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let read = tokio::spawn(async move { done_rx.await.err().map(|_| CloseReason::Reset) });
+        let write = tokio::spawn(async move { Ok(rx) });
+        let joined = tokio::spawn(join(read, write));
+        tokio::task::yield_now().await;
+        assert!(!joined.is_finished());
+        done_tx.send(()).unwrap();
+        assert_eq!(joined.await.unwrap(), CloseReason::Closed);
+        drop(tx);
+    }
+}
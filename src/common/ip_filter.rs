@@ -0,0 +1,129 @@
+//! 隧道访问者 IP 过滤 (CIDR 白名单/黑名单)
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// 访问者 IP 过滤规则：先匹配黑名单，白名单非空时只放行白名单内的地址
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// 解析 CIDR 列表，单个 IP 视为 /32 (IPv6 为 /128)
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            allow: allow
+                .iter()
+                .map(|s| parse_net(s))
+                .collect::<Result<_, _>>()?,
+            deny: deny
+                .iter()
+                .map(|s| parse_net(s))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    #[allow(dead_code)] // 仅服务端使用
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+fn parse_net(s: &str) -> Result<IpNet, String> {
+    let s = s.trim();
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("无效的 IP/CIDR: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let list = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        IpFilter::parse(&list(allow), &list(deny)).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let f = filter(&["10.0.0.0/8"], &["10.1.0.0/16", "10.2.3.4"]);
+        assert!(f.is_allowed(ip("10.0.0.1")));
+        assert!(!f.is_allowed(ip("10.1.2.3")));
+        assert!(!f.is_allowed(ip("10.2.3.4")));
+        assert!(f.is_allowed(ip("10.2.3.5")));
+        assert!(!f.is_allowed(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn empty_allow_list_allows_all_but_denied() {
+        let f = filter(&[], &[]);
+        assert!(f.is_allowed(ip("203.0.113.7")));
+        assert!(f.is_allowed(ip("2001:db8::1")));
+
+        let f = filter(&[], &["203.0.113.0/24"]);
+        assert!(!f.is_allowed(ip("203.0.113.7")));
+        assert!(f.is_allowed(ip("198.51.100.7")));
+    }
+
+    #[test]
+    fn bare_ip_is_single_host() {
+        let f = filter(&["192.168.1.10", "2001:db8::1"], &[]);
+        assert!(f.is_allowed(ip("192.168.1.10")));
+        assert!(!f.is_allowed(ip("192.168.1.11")));
+        assert!(f.is_allowed(ip("2001:db8::1")));
+        assert!(!f.is_allowed(ip("2001:db8::2")));
+
+        let f = filter(&["192.168.1.0/24", " 2001:db8::/32 "], &[]);
+        assert!(f.is_allowed(ip("192.168.1.11")));
+        assert!(f.is_allowed(ip("2001:db8:1::5")));
+        assert!(!f.is_allowed(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peer_matches_ipv4_rules() {
+        // 双栈监听时 IPv4 访问者显示为 ::ffff:a.b.c.d，不能借此绕过黑名单
+        let f = filter(&["10.0.0.0/8"], &["10.9.0.0/16"]);
+        assert!(f.is_allowed(ip("::ffff:10.0.0.1")));
+        assert!(!f.is_allowed(ip("::ffff:10.9.0.1")));
+        assert!(!f.is_allowed(ip("::ffff:192.168.1.1")));
+
+        let f = filter(&[], &["127.0.0.1"]);
+        assert!(!f.is_allowed(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        for bad in [
+            "",
+            "abc",
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0/8",
+            "10.0.0.256",
+            "10.0.0.0/",
+            "10.0.0.0/8/8",
+        ] {
+            assert!(
+                IpFilter::parse(&[bad.to_string()], &[]).is_err(),
+                "allow {:?}",
+                bad
+            );
+            assert!(
+                IpFilter::parse(&[], &[bad.to_string()]).is_err(),
+                "deny {:?}",
+                bad
+            );
+        }
+    }
+}
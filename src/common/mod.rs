@@ -1 +1,9 @@
+pub mod auth;
+pub mod codec;
+pub mod compress;
+pub mod flow;
+pub mod half_close;
+pub mod ip_filter;
 pub mod protocol;
+pub mod queue;
+pub mod tls;
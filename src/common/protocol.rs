@@ -1,14 +1,42 @@
 //! WebSocket 协议消息定义
 
+use crate::common::compress;
+use crate::common::ip_filter::IpFilter;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 协议版本
+/// - 1: 初始版本，Register 直接发送
+/// - 2: 增加 Hello/Auth 挑战-应答握手
+/// - 3: Register/RegisterResponse 协商协议版本和能力
+/// - 4: conn_id 改为服务端分配的数字流 ID，Data 使用紧凑二进制帧 (见 [`frame`])
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// 连接 (流) ID，由服务端分配，不为 0
+pub type StreamId = u32;
+
+/// 支持 Hello/Auth 握手的最低协议版本
+#[allow(dead_code)] // 仅服务端使用
+pub const MIN_AUTH_PROTOCOL_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TunnelType {
     Tcp,
     Udp,
 }
 
+impl std::str::FromStr for TunnelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(TunnelType::Tcp),
+            "udp" => Ok(TunnelType::Udp),
+            _ => Err(format!("未知隧道类型: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientInfo {
     pub id: String,
@@ -27,6 +55,25 @@ pub struct TunnelConfig {
     pub local_port: u16,
     pub remote_port: Option<u16>,
     pub name: Option<String>,
+    /// 允许访问的来源 IP/CIDR，为空表示不限制
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// 拒绝访问的来源 IP/CIDR，优先于 allow
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+    /// 端到端加密，数据在客户端与访问端之间加密，服务端只转发密文
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub e2e: bool,
+    /// 私密隧道，设置后服务端不开放端口，只有持相同密钥的访问端 (`cec-tunnel visit`) 可以连接。
+    /// 服务端不知道密钥，只把访问端的 MAC 转给隧道客户端校验 (见 [`VisitorAuth`])
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+    /// 私密隧道密钥，只保存在隧道客户端本地，不随注册消息发送
+    #[serde(skip)]
+    pub secret_key: Option<String>,
+    /// 压缩阈值 (字节)，不小于该长度的 Data 压缩后发送 (见 `common::compress`)，None 表示不压缩
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,36 +90,110 @@ pub struct TunnelInfo {
     pub bytes_recv: u64,
     pub created_at: String,
     pub last_active_at: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub e2e: bool,
+    /// 私密隧道，无服务端端口 (server_port 为 0)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+    /// 压缩阈值 (字节)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum WsMessage {
+    /// 握手挑战，WebSocket 升级后立即发送（服务端 → 客户端）
+    Hello {
+        protocol_version: u32,
+        nonce: String,
+    },
+    /// 握手应答，须在 Register 之前发送（客户端 → 服务端）
+    Auth {
+        protocol_version: u32,
+        client_name: String,
+        /// HMAC-SHA256(nonce:client_name)，以 Token 为密钥；未配置 Token 时为空
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mac: Option<String>,
+        /// 使用签名 Token 时携带其不含签名的部分，服务端据此还原完整 Token
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signed_claims: Option<String>,
+    },
     Register {
         client: ClientInfo,
         tunnels: Vec<TunnelConfig>,
+        /// 客户端协议版本，旧版客户端不发送 (为 0)
+        #[serde(default)]
+        protocol_version: u32,
+        /// 客户端支持的能力，见 [`capability`]；旧版客户端不发送
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Vec<String>>,
     },
     RegisterResponse {
         success: bool,
         client_id: String,
         tunnels: Vec<TunnelInfo>,
         message: Option<String>,
+        /// 双方协议版本中较低的一个，旧版服务端不发送 (为 0)
+        #[serde(default)]
+        protocol_version: u32,
+        /// 双方共同支持的能力，本会话只使用其中的能力；旧版服务端不发送
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Vec<String>>,
     },
     NewConnection {
         tunnel_id: String,
-        conn_id: String,
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
+        /// 访问端连接私密隧道时的认证信息，隧道客户端以密钥校验后才连接本地服务
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        visitor: Option<VisitorAuth>,
     },
+    /// 客户端已连上本地服务，服务端此后才转发外部连接的数据
     ConnectionReady {
         tunnel_id: String,
-        conn_id: String,
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
+    },
+    /// 客户端连接本地服务失败（客户端 → 服务端），旧版客户端以 CloseConnection 代替
+    ConnectionFailed {
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
+        /// connect_failed 或 denied
+        #[serde(default)]
+        reason: CloseReason,
+        message: String,
     },
     Data {
-        conn_id: String,
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
         #[serde(with = "base64_bytes")]
         data: Vec<u8>,
+        /// data 经 deflate 压缩，仅在协商了 `deflate` 的会话中出现
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        compressed: bool,
     },
     CloseConnection {
-        conn_id: String,
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
+        /// 旧版两端不发送 (为 unknown)
+        #[serde(default)]
+        reason: CloseReason,
+    },
+    /// 归还发送额度，接收方把数据写入本地连接后发送 (见 `common::flow`)
+    WindowUpdate {
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
+        credit: u32,
+    },
+    /// 发送方的本地连接已关闭写方向 (TCP FIN)，之后该方向不再有 Data，另一方向仍可传输
+    HalfClose {
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
     },
     /// 服务端动态下发隧道（服务端 → 客户端）
     AddTunnel {
@@ -86,6 +207,30 @@ pub enum WsMessage {
         tunnel: Option<TunnelInfo>,
         message: Option<String>,
     },
+    /// 访问端请求经服务端连接其他客户端的隧道（访问端 → 服务端）
+    VisitorOpen {
+        /// 访问端生成的请求 ID，用于对应 VisitorAccept
+        request_id: String,
+        /// 提供隧道的客户端名称
+        client_name: String,
+        tunnel_name: String,
+        /// HMAC-SHA256(nonce:request_id)，以隧道密钥为密钥，nonce 为本会话 Hello 中的 nonce；
+        /// 仅私密隧道需要，服务端转给隧道客户端校验
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mac: Option<String>,
+    },
+    /// 访问端连接结果，成功表示隧道客户端已连上本地服务（服务端 → 访问端）
+    VisitorAccept {
+        request_id: String,
+        /// 服务端为访问端连接分配的流 ID，失败时为 0
+        #[serde(with = "stream_id")]
+        conn_id: StreamId,
+        success: bool,
+        message: Option<String>,
+        /// 隧道的压缩阈值，访问端发送的数据按此压缩
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compress: Option<u32>,
+    },
     Ping {
         timestamp: i64,
     },
@@ -93,11 +238,230 @@ pub enum WsMessage {
         timestamp: i64,
     },
     Error {
-        code: i32,
+        code: ErrorCode,
         message: String,
     },
 }
 
+/// 访问端的私密隧道认证信息，由服务端原样转给隧道客户端，密钥不经过服务端
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VisitorAuth {
+    /// 访问端会话 Hello 中的 nonce
+    pub nonce: String,
+    pub request_id: String,
+    /// HMAC-SHA256(nonce:request_id)，以隧道密钥为密钥
+    pub mac: String,
+}
+
+/// 连接关闭原因，随 CloseConnection / ConnectionFailed 发送，服务端按隧道计数
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// 发送方的本地连接正常关闭
+    Closed,
+    /// 发送方的本地连接读写出错 (如被重置)
+    Reset,
+    /// 另一端的连接已关闭，由服务端转告，不重复计数
+    PeerClosed,
+    /// 隧道客户端连接本地服务失败
+    ConnectFailed,
+    /// 目标不在隧道客户端 --allow-target 范围内，或缺少 e2e 密钥
+    Denied,
+    /// 隧道客户端校验访问端的私密隧道密钥失败
+    AuthFailed,
+    /// 等待 ConnectionReady 超时
+    ReadyTimeout,
+    /// 对端发送的数据超出流量控制窗口
+    FlowControl,
+    /// 对端发送的压缩数据无法解压
+    InvalidData,
+    /// 隧道被关闭
+    TunnelClosed,
+    /// 所属客户端的 WebSocket 会话断开
+    Disconnected,
+    /// 旧版对端未发送原因，或本端不认识的原因
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CloseReason::Closed => "连接关闭",
+            CloseReason::Reset => "连接出错",
+            CloseReason::PeerClosed => "对端关闭",
+            CloseReason::ConnectFailed => "连接本地服务失败",
+            CloseReason::Denied => "目标被拒绝",
+            CloseReason::AuthFailed => "密钥错误",
+            CloseReason::ReadyTimeout => "等待就绪超时",
+            CloseReason::FlowControl => "超出流量控制窗口",
+            CloseReason::InvalidData => "数据无法解压",
+            CloseReason::TunnelClosed => "隧道关闭",
+            CloseReason::Disconnected => "客户端断开",
+            CloseReason::Unknown => "未知原因",
+        };
+        f.write_str(s)
+    }
+}
+
+/// `WsMessage::Error` 的错误码，线上格式为整数，与旧版本兼容
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "i32", into = "i32")]
+pub enum ErrorCode {
+    /// Token 缺失或错误，客户端不应重试
+    AuthFailed,
+    /// 超出 Token 授权范围（客户端名称、端口、隧道类型、数量）
+    Forbidden,
+    /// 客户端协议版本过旧（未进行握手）
+    UnsupportedVersion,
+    /// 签名 Token 已过期
+    TokenExpired,
+    /// 本端不认识的错误码，按可恢复错误处理
+    Other(i32),
+}
+
+impl ErrorCode {
+    /// 是否为不可恢复的错误（客户端收到后应停止重连）
+    #[allow(dead_code)] // 仅客户端使用
+    pub fn is_fatal(self) -> bool {
+        !matches!(self, ErrorCode::Other(_))
+    }
+}
+
+impl From<i32> for ErrorCode {
+    fn from(code: i32) -> Self {
+        match code {
+            401 => ErrorCode::AuthFailed,
+            403 => ErrorCode::Forbidden,
+            426 => ErrorCode::UnsupportedVersion,
+            498 => ErrorCode::TokenExpired,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for i32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::AuthFailed => 401,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::UnsupportedVersion => 426,
+            ErrorCode::TokenExpired => 498,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", i32::from(*self))
+    }
+}
+
+/// Register/RegisterResponse 协商的能力
+pub mod capability {
+    /// Data 等消息使用紧凑二进制帧 (见 [`super::frame`])，否则为 JSON (base64)
+    pub const STREAM_FRAMES: &str = "stream_frames";
+    /// 隧道端到端加密，服务端下发 e2e 隧道前要求客户端支持
+    pub const E2E: &str = "e2e";
+    /// 按连接的流量控制 (WindowUpdate)
+    pub const FLOW_CONTROL: &str = "flow_control";
+    /// TCP 半关闭 (HalfClose)，否则任一方向结束即关闭整个连接
+    pub const HALF_CLOSE: &str = "half_close";
+    /// 连接本地服务失败时回复 ConnectionFailed (含原因)
+    pub const CONNECTION_FAILED: &str = "connection_failed";
+    /// Data 可以用 deflate 压缩 (见 `common::compress`)
+    pub const DEFLATE: &str = "deflate";
+
+    /// 本端支持的能力
+    pub const SUPPORTED: &[&str] = &[
+        STREAM_FRAMES,
+        E2E,
+        FLOW_CONTROL,
+        HALF_CLOSE,
+        CONNECTION_FAILED,
+        DEFLATE,
+    ];
+
+    /// 取对端能力与本端能力的交集，对端不发送能力列表 (协议版本 3 之前) 时为空
+    pub fn negotiate(peer: Option<&[String]>) -> Vec<String> {
+        SUPPORTED
+            .iter()
+            .filter(|c| peer.unwrap_or_default().iter().any(|p| p == *c))
+            .map(|c| c.to_string())
+            .collect()
+    }
+
+    /// 本端支持的全部能力，用于 Register
+    #[allow(dead_code)] // 仅客户端使用
+    pub fn supported() -> Vec<String> {
+        SUPPORTED.iter().map(|c| c.to_string()).collect()
+    }
+}
+
+/// 紧凑二进制帧，协商 `stream_frames` 后用于 WebSocket Binary 消息
+///
+/// ```text
+/// | 类型 u8 | 标志 u8 | 流 ID u32 | 长度 u32 | 数据 (长度字节) |
+/// ```
+///
+/// 整数均为大端序，一个 Binary 消息可以包含多个帧。编解码见 `common::codec`。
+pub mod frame {
+    /// 帧头长度
+    pub const HEADER_LEN: usize = 10;
+
+    /// Data 帧标志：数据经 deflate 压缩
+    pub const FLAG_COMPRESSED: u8 = 0x01;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(u8)]
+    pub enum FrameType {
+        /// 连接数据，对应 `WsMessage::Data`
+        Data = 0,
+        /// 归还发送额度，数据为 u32 额度，对应 `WsMessage::WindowUpdate`
+        WindowUpdate = 1,
+        /// 关闭写方向，无数据，对应 `WsMessage::HalfClose`
+        HalfClose = 2,
+    }
+
+    impl TryFrom<u8> for FrameType {
+        type Error = u8;
+
+        fn try_from(value: u8) -> Result<Self, Self::Error> {
+            match value {
+                0 => Ok(FrameType::Data),
+                1 => Ok(FrameType::WindowUpdate),
+                2 => Ok(FrameType::HalfClose),
+                other => Err(other),
+            }
+        }
+    }
+}
+
+/// JSON 中的流 ID 以字符串表示，旧版客户端把 conn_id 当作不透明字符串原样回传
+mod stream_id {
+    use super::StreamId;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(id: &StreamId, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(id)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<StreamId, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 mod base64_bytes {
     use base64::{engine::general_purpose::STANDARD, Engine};
     use serde::{Deserialize, Deserializer, Serializer};
@@ -120,30 +484,27 @@ mod base64_bytes {
 
 impl TunnelConfig {
     /// 解析隧道配置字符串
-    /// 格式: type:local_port:remote_port 或 type:local_addr:local_port:remote_port
+    /// 格式: type:local_port:remote_port 或 type:local_addr:local_port:remote_port，
+    /// 可追加选项 `?allow=10.0.0.0/8,1.2.3.4&deny=10.0.0.1&e2e`，
+    /// `compress` 或 `compress=阈值` 压缩达到阈值的数据 (不能与 e2e 同时使用)；
+    /// 私密隧道使用 `?sk=密钥&name=隧道名称`，此时忽略 remote_port；
+    /// 只写 `sk` 时使用 `secret` (客户端 `--secret-file` 中的密钥)，未提供时解析失败
     #[allow(dead_code)] // 仅客户端使用
-    pub fn parse(s: &str) -> Option<Self> {
-        let parts: Vec<&str> = s.split(':').collect();
+    pub fn parse(s: &str, secret: Option<&str>) -> Option<Self> {
+        let (spec, options) = s.split_once('?').unwrap_or((s, ""));
+        let parts: Vec<&str> = spec.split(':').collect();
 
         let (tunnel_type, local_addr, local_port, remote_port) = match parts.len() {
             // type:local_port:remote_port
             3 => {
-                let t = match parts[0] {
-                    "tcp" => TunnelType::Tcp,
-                    "udp" => TunnelType::Udp,
-                    _ => return None,
-                };
+                let t: TunnelType = parts[0].parse().ok()?;
                 let lp: u16 = parts[1].parse().ok()?;
                 let rp: u16 = parts[2].parse().ok()?;
                 (t, "127.0.0.1".to_string(), lp, Some(rp))
             }
             // type:local_addr:local_port:remote_port
             4 => {
-                let t = match parts[0] {
-                    "tcp" => TunnelType::Tcp,
-                    "udp" => TunnelType::Udp,
-                    _ => return None,
-                };
+                let t: TunnelType = parts[0].parse().ok()?;
                 let la = parts[1].to_string();
                 let lp: u16 = parts[2].parse().ok()?;
                 let rp: u16 = parts[3].parse().ok()?;
@@ -152,12 +513,96 @@ impl TunnelConfig {
             _ => return None,
         };
 
-        Some(Self {
+        let mut config = Self {
             tunnel_type,
             local_addr,
             local_port,
             remote_port,
             name: None,
-        })
+            allow: Vec::new(),
+            deny: Vec::new(),
+            e2e: false,
+            secret: false,
+            secret_key: None,
+            compress: None,
+        };
+        for option in options.split('&').filter(|o| !o.is_empty()) {
+            if option == "e2e" {
+                config.e2e = true;
+                continue;
+            }
+            if option == "sk" {
+                config.secret_key = Some(secret?.to_string());
+                continue;
+            }
+            if option == "compress" {
+                config.compress = Some(compress::DEFAULT_THRESHOLD);
+                continue;
+            }
+            let (key, value) = option.split_once('=')?;
+            let list = value
+                .split(',')
+                .filter(|v| !v.is_empty())
+                .map(str::to_string);
+            match key {
+                "allow" => config.allow.extend(list),
+                "deny" => config.deny.extend(list),
+                "sk" if !value.is_empty() => config.secret_key = Some(value.to_string()),
+                "name" if !value.is_empty() => config.name = Some(value.to_string()),
+                "compress" => config.compress = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+        IpFilter::parse(&config.allow, &config.deny).ok()?;
+        if config.secret_key.is_some() {
+            // 私密隧道没有服务端端口，来源限制无意义
+            if !config.allow.is_empty() || !config.deny.is_empty() {
+                return None;
+            }
+            config.secret = true;
+            config.remote_port = None;
+        }
+        // e2e 隧道转发的是密文，压缩没有效果
+        if config.e2e && config.compress.is_some() {
+            return None;
+        }
+
+        Some(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_code_wire_format() {
+        let msg = WsMessage::Error {
+            code: ErrorCode::TokenExpired,
+            message: "expired".to_string(),
+        };
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["code"], 498);
+
+        // 旧版本发送的整数错误码，不认识的保留原值
+        for (code, expected) in [
+            (401, ErrorCode::AuthFailed),
+            (403, ErrorCode::Forbidden),
+            (426, ErrorCode::UnsupportedVersion),
+            (498, ErrorCode::TokenExpired),
+            (500, ErrorCode::Other(500)),
+        ] {
+            let json = serde_json::json!({"type": "error", "code": code, "message": ""});
+            match serde_json::from_value::<WsMessage>(json).unwrap() {
+                WsMessage::Error { code: parsed, .. } => {
+                    assert_eq!(parsed, expected);
+                    assert_eq!(i32::from(parsed), code);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(ErrorCode::AuthFailed.is_fatal());
+        assert!(ErrorCode::TokenExpired.is_fatal());
+        assert!(!ErrorCode::Other(500).is_fatal());
     }
 }
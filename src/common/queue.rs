@@ -0,0 +1,150 @@
+//! WebSocket 会话的发送队列
+//!
+//! 所有连接发往同一 WebSocket 的消息共用一个有界队列。Data 按字节数占用会话的发送额度
+//! ([`QUEUE_LIMIT`])，发送任务取出后归还，额度用完时读取本地连接的任务暂停，
+//! WebSocket 链路慢时数据不会在内存中无限堆积。控制消息不等待，队列满时关闭会话。
+
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{self, error::SendError, error::TrySendError};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::common::protocol::WsMessage;
+
+/// 每个会话队列中最多积压的 Data 字节数
+pub const QUEUE_LIMIT: usize = 1024 * 1024;
+
+/// 单条 Data 至少占用的额度，使队列中的 Data 不超过 `QUEUE_LIMIT / MIN_DATA_CHARGE` 条
+const MIN_DATA_CHARGE: usize = 1024;
+
+/// 为控制消息保留的队列容量
+const CONTROL_CAPACITY: usize = 8192;
+
+/// 创建会话的发送队列
+pub fn channel() -> (SendQueue, QueueReceiver) {
+    let (tx, rx) = mpsc::channel(QUEUE_LIMIT / MIN_DATA_CHARGE + CONTROL_CAPACITY);
+    let budget = Arc::new(Semaphore::new(QUEUE_LIMIT));
+    (
+        SendQueue {
+            tx,
+            budget: Arc::clone(&budget),
+        },
+        QueueReceiver { rx, budget },
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    /// (消息, 占用的额度)
+    tx: mpsc::Sender<(WsMessage, usize)>,
+    budget: Arc<Semaphore>,
+}
+
+impl SendQueue {
+    /// 发送控制消息，不占用额度，会话已关闭时返回错误
+    ///
+    /// 队列已满说明对端长时间不读取，关闭会话而不是继续堆积。
+    pub fn send(&self, msg: WsMessage) -> Result<(), SendError<()>> {
+        match self.tx.try_send((msg, 0)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                warn!("会话发送队列已满，关闭会话");
+                self.budget.close();
+                Err(SendError(()))
+            }
+            Err(TrySendError::Closed(_)) => Err(SendError(())),
+        }
+    }
+
+    /// 发送 Data，等待会话有额度后入队，会话已关闭时返回 false
+    pub async fn send_data(&self, msg: WsMessage) -> bool {
+        let charge = data_charge(&msg);
+        match self.budget.acquire_many(charge as u32).await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return false,
+        }
+        self.tx.send((msg, charge)).await.is_ok()
+    }
+}
+
+pub struct QueueReceiver {
+    rx: mpsc::Receiver<(WsMessage, usize)>,
+    budget: Arc<Semaphore>,
+}
+
+impl QueueReceiver {
+    /// 取出下一条消息，Data 的额度在取出时归还；会话因队列已满被关闭后返回 None
+    pub async fn recv(&mut self) -> Option<WsMessage> {
+        if self.budget.is_closed() {
+            return None;
+        }
+        let (msg, charge) = self.rx.recv().await?;
+        self.budget.add_permits(charge);
+        Some(msg)
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        // 唤醒等待额度的发送方，会话结束后额度不会再归还
+        self.budget.close();
+    }
+}
+
+/// Data 占用的额度，超过队列上限的单条消息按上限计算，避免永远等不到额度
+fn data_charge(msg: &WsMessage) -> usize {
+    match msg {
+        WsMessage::Data { data, .. } => data.len().clamp(MIN_DATA_CHARGE, QUEUE_LIMIT),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::protocol::CloseReason;
+
+    fn data(len: usize) -> WsMessage {
+        WsMessage::Data {
+            conn_id: 1,
+            data: vec![0; len],
+            compressed: false,
+        }
+    }
+
+    fn close() -> WsMessage {
+        WsMessage::CloseConnection {
+            conn_id: 1,
+            reason: CloseReason::Closed,
+        }
+    }
+
+    #[test]
+    fn small_data_charged_minimum() {
+        assert_eq!(data_charge(&data(1)), MIN_DATA_CHARGE);
+        assert_eq!(data_charge(&data(QUEUE_LIMIT * 2)), QUEUE_LIMIT);
+        assert_eq!(data_charge(&close()), 0);
+    }
+
+    #[tokio::test]
+    async fn data_budget_returned_on_recv() {
+        let (tx, mut rx) = channel();
+        assert!(tx.send_data(data(QUEUE_LIMIT)).await);
+        assert_eq!(tx.budget.available_permits(), 0);
+        assert!(rx.recv().await.is_some());
+        assert_eq!(tx.budget.available_permits(), QUEUE_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn full_control_queue_closes_session() {
+        let (tx, mut rx) = channel();
+        let capacity = QUEUE_LIMIT / MIN_DATA_CHARGE + CONTROL_CAPACITY;
+        for _ in 0..capacity {
+            assert!(tx.send(close()).is_ok());
+        }
+        assert!(tx.send(close()).is_err());
+        assert!(!tx.send_data(data(1)).await);
+        assert!(rx.recv().await.is_none());
+    }
+}
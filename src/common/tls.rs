@@ -0,0 +1,22 @@
+//! PEM 证书/私钥加载
+
+use anyhow::{Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// 读取 PEM 证书链
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("读取证书 {} 失败", path))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("解析证书 {} 失败", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("证书文件 {} 中没有证书", path);
+    }
+    Ok(certs)
+}
+
+/// 读取 PEM 私钥 (PKCS#8 / PKCS#1 / SEC1)
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).with_context(|| format!("读取私钥 {} 失败", path))
+}
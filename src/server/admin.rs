@@ -0,0 +1,309 @@
+//! HTTP 管理 API 认证
+//!
+//! 管理 API Key 与客户端注册 Token 相互独立，请求通过以下任一请求头携带：
+//! - `Authorization: Bearer <key>`
+//! - `X-API-Key: <key>`
+//!
+//! 每个 API Key 带有角色，权限逐级包含：`read` < `operator` < `admin`。
+
+use crate::audit::{AuditAction, AuditEvent, AuditLog};
+use crate::limiter::RateLimiter;
+use anyhow::{bail, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// API Key 角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// 只读：查看状态、客户端和隧道
+    Read,
+    /// 运维：只读权限 + 添加/关闭隧道、查询 Token
+    Operator,
+    /// 管理员：全部权限，包括断开客户端、变更 Token
+    Admin,
+}
+
+impl Role {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "read" => Some(Role::Read),
+            "operator" => Some(Role::Operator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Read => "read",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// 通过认证的 API Key 标识 (`角色:SHA-256 指纹前 8 位`)，由 [`require_role`] 写入请求扩展，
+/// 用于审计日志，不包含 Key 本身
+#[derive(Debug, Clone)]
+pub struct ApiIdentity(pub String);
+
+impl ApiIdentity {
+    fn new(role: Role, key: &str) -> Self {
+        Self(format!("{}:{}", role.as_str(), fingerprint(key)))
+    }
+}
+
+/// Key 的 SHA-256 指纹前 8 位，审计日志中用来区分 Key 而不泄露 Key 本身
+fn fingerprint(key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    digest.as_ref()[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Debug)]
+struct ApiKey {
+    /// Key 的 SHA-256，按定长摘要比较，避免比较耗时泄露 Key 内容
+    digest: [u8; 32],
+    role: Role,
+}
+
+/// 管理 API Key 列表，为空时不做认证
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    /// 解析 `--api-key` 参数，格式 `[角色:]key`，未指定角色时为 admin，Key 不能为空
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut keys = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let (role, key) = spec
+                .split_once(':')
+                .and_then(|(r, k)| Role::parse(r).map(|r| (r, k)))
+                .unwrap_or((Role::Admin, spec.as_str()));
+            if key.trim().is_empty() {
+                bail!("--api-key {:?} 的 Key 为空", spec);
+            }
+            keys.push(ApiKey {
+                digest: key_digest(key),
+                role,
+            });
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn role_of(&self, key: &str) -> Option<Role> {
+        let digest = key_digest(key);
+        self.keys
+            .iter()
+            .find(|k| constant_time_eq(&k.digest, &digest))
+            .map(|k| k.role)
+    }
+}
+
+fn key_digest(key: &str) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, key.as_bytes()).as_ref());
+    out
+}
+
+/// 常量时间比较定长摘要
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 路由级权限要求，作为 [`require_role`] 中间件的状态
+#[derive(Clone)]
+pub struct RequireRole {
+    keys: Arc<ApiKeys>,
+    role: Role,
+    audit: Arc<AuditLog>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RequireRole {
+    pub fn new(
+        keys: &Arc<ApiKeys>,
+        role: Role,
+        audit: &Arc<AuditLog>,
+        limiter: &Arc<RateLimiter>,
+    ) -> Self {
+        Self {
+            keys: Arc::clone(keys),
+            role,
+            audit: Arc::clone(audit),
+            limiter: Arc::clone(limiter),
+        }
+    }
+}
+
+/// 从请求头提取 API Key，`Authorization` 不是 Bearer 方案时回退到 `X-API-Key`
+fn extract_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer
+        .or_else(|| headers.get("x-api-key")?.to_str().ok())
+        .map(str::trim)
+}
+
+fn reject(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        Json(json!({ "code": status.as_u16(), "message": message, "data": null })),
+    )
+        .into_response()
+}
+
+/// 管理 API 认证中间件：缺少凭证返回 401，凭证无效或角色权限不足返回 403，
+/// 来源 IP 因认证失败过多被封禁时返回 429
+///
+/// 认证失败写入审计日志；缺少或无效的 Key 计入来源 IP 的失败次数，与 /tunnel 共用封禁
+pub async fn require_role(
+    State(required): State<RequireRole>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if !required.keys.is_enabled() {
+        return next.run(req).await;
+    }
+    let ip = addr.ip();
+    if let Err(e) = required.limiter.check_banned(ip) {
+        return reject(StatusCode::TOO_MANY_REQUESTS, &e);
+    }
+    let key = extract_key(req.headers());
+    let role = key.and_then(|key| required.keys.role_of(key));
+    let (status, message, api_key) = match (key, role) {
+        (Some(key), Some(role)) if role >= required.role => {
+            let identity = ApiIdentity::new(role, key);
+            req.extensions_mut().insert(identity);
+            return next.run(req).await;
+        }
+        (None, _) => (StatusCode::UNAUTHORIZED, "缺少 API Key", None),
+        (Some(key), None) => (
+            StatusCode::FORBIDDEN,
+            "API Key 无效",
+            Some(fingerprint(key)),
+        ),
+        (Some(key), Some(role)) => (
+            StatusCode::FORBIDDEN,
+            "API Key 权限不足",
+            Some(ApiIdentity::new(role, key).0),
+        ),
+    };
+    required.audit.record(
+        AuditEvent::new(AuditAction::AuthFailure)
+            .ip(ip)
+            .api_key(api_key)
+            .detail(format!(
+                "{} {}: {}",
+                req.method(),
+                req.uri().path(),
+                message
+            )),
+    );
+    // 权限不足的 Key 本身有效，不计入失败次数
+    if role.is_none() {
+        required.limiter.record_failure(ip);
+    }
+    reject(status, message)
+}
+
+/// 根据 `--cors-origin` 构建 CORS 层，`*` 表示允许任意来源，未配置时不返回 CORS 头
+pub fn cors_layer(origins: &[String]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|o| HeaderValue::from_str(o).ok()))
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::HeaderName::from_static("x-api-key"),
+            ]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn extract_bearer_or_header() {
+        let h = headers(&[("authorization", "Bearer abc ")]);
+        assert_eq!(extract_key(&h), Some("abc"));
+        let h = headers(&[("x-api-key", "xyz")]);
+        assert_eq!(extract_key(&h), Some("xyz"));
+        assert_eq!(extract_key(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn extract_falls_back_when_not_bearer() {
+        let h = headers(&[
+            ("authorization", "Basic dXNlcjpwYXNz"),
+            ("x-api-key", "xyz"),
+        ]);
+        assert_eq!(extract_key(&h), Some("xyz"));
+        let h = headers(&[("authorization", "Basic dXNlcjpwYXNz")]);
+        assert_eq!(extract_key(&h), None);
+    }
+
+    #[test]
+    fn parse_roles() {
+        let keys = ApiKeys::parse(&[
+            "secret".into(),
+            "read:monitor".into(),
+            "operator:ops".into(),
+        ])
+        .unwrap();
+        assert_eq!(keys.role_of("secret"), Some(Role::Admin));
+        assert_eq!(keys.role_of("monitor"), Some(Role::Read));
+        assert_eq!(keys.role_of("ops"), Some(Role::Operator));
+        assert_eq!(keys.role_of("read:monitor"), None);
+        assert_eq!(keys.role_of("secre"), None);
+        assert_eq!(keys.role_of(""), None);
+    }
+
+    #[test]
+    fn parse_rejects_empty_key() {
+        assert!(ApiKeys::parse(&["read:".into()]).is_err());
+        assert!(ApiKeys::parse(&["admin: ".into()]).is_err());
+        assert!(ApiKeys::parse(&["".into()]).is_err());
+    }
+}
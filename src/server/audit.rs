@@ -0,0 +1,252 @@
+//! 审计日志
+//!
+//! 记录管理操作 (关闭隧道、断开客户端、添加隧道、Token 变更) 和客户端注册相关事件
+//! (注册、注册被拒、认证失败、同名顶替)。配置 `--audit-log` 时以 JSON Lines 追加写入文件，
+//! 否则只在内存中保留最近的事件。
+//!
+//! 文件由独立的写入线程追加，记录事件只是入队，不会阻塞异步任务。
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::warn;
+
+/// 未配置审计文件时内存中保留的事件数
+const MEMORY_CAPACITY: usize = 1000;
+/// 等待写入审计文件的事件上限，写入跟不上时丢弃新事件
+const WRITE_QUEUE: usize = 4096;
+/// /api/audit 默认返回的事件数
+const DEFAULT_LIMIT: usize = 100;
+/// /api/audit 单次最多返回的事件数
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Register,
+    RegisterRejected,
+    AuthFailure,
+    /// 同名客户端注册时顶替旧客户端
+    Evict,
+    DisconnectClient,
+    CloseTunnel,
+    AddTunnel,
+    CreateToken,
+    RevokeToken,
+    ExpireToken,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub time: DateTime<Utc>,
+    pub action: AuditAction,
+    /// 来源 IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    /// 管理 API Key 标识 (角色:指纹)，未启用 API 认证时为空
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction) -> Self {
+        Self {
+            time: Utc::now(),
+            action,
+            ip: None,
+            api_key: None,
+            client_id: None,
+            client_name: None,
+            tunnel_id: None,
+            token_id: None,
+            detail: None,
+        }
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip.to_canonical());
+        self
+    }
+
+    pub fn api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    pub fn client_name(mut self, client_name: impl Into<String>) -> Self {
+        self.client_name = Some(client_name.into());
+        self
+    }
+
+    pub fn tunnel_id(mut self, tunnel_id: impl Into<String>) -> Self {
+        self.tunnel_id = Some(tunnel_id.into());
+        self
+    }
+
+    pub fn token_id(mut self, token_id: impl Into<String>) -> Self {
+        self.token_id = Some(token_id.into());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// /api/audit 查询条件
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// 起始时间 (RFC 3339，含)
+    pub since: Option<DateTime<Utc>>,
+    /// 结束时间 (RFC 3339，不含)
+    pub until: Option<DateTime<Utc>>,
+    pub action: Option<AuditAction>,
+    /// 客户端 ID 或名称
+    pub client: Option<String>,
+    /// 返回最近的条数，默认 100，最多 1000
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.since.is_none_or(|t| event.time >= t)
+            && self.until.is_none_or(|t| event.time < t)
+            && self.action.is_none_or(|a| event.action == a)
+            && self.client.as_ref().is_none_or(|c| {
+                event.client_id.as_ref() == Some(c) || event.client_name.as_ref() == Some(c)
+            })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<(PathBuf, SyncSender<String>)>,
+    memory: Mutex<VecDeque<AuditEvent>>,
+}
+
+impl AuditLog {
+    /// 以追加方式打开审计文件，不存在时创建，并启动写入线程
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("打开审计日志 {} 失败: {}", path, e))?;
+        let path = PathBuf::from(path);
+        let (tx, rx) = mpsc::sync_channel::<String>(WRITE_QUEUE);
+        let writer_path = path.clone();
+        thread::Builder::new()
+            .name("audit-writer".into())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        warn!("写入审计日志 {} 失败: {}", writer_path.display(), e);
+                    }
+                }
+            })
+            .map_err(|e| anyhow::anyhow!("启动审计日志写入线程失败: {}", e))?;
+        Ok(Self {
+            file: Some((path, tx)),
+            memory: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        match &self.file {
+            Some((path, writer)) => {
+                let line = match serde_json::to_string(&event) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        warn!("审计事件序列化失败: {}", e);
+                        return;
+                    }
+                };
+                match writer.try_send(line) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        warn!("审计日志 {} 写入积压，丢弃事件", path.display());
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        warn!("审计日志 {} 写入线程已退出，丢弃事件", path.display());
+                    }
+                }
+            }
+            None => {
+                let mut memory = self.memory.lock().unwrap();
+                if memory.len() >= MEMORY_CAPACITY {
+                    memory.pop_front();
+                }
+                memory.push_back(event);
+            }
+        }
+    }
+
+    /// 按条件查询，返回 (最近的 limit 条事件，按时间顺序, 匹配总数)
+    ///
+    /// 审计文件逐行读取，只保留最近的 limit 条匹配事件，内存占用与文件大小无关。
+    /// 刚记录、尚在写入队列中的事件可能不会出现在结果中。
+    pub async fn query(&self, query: &AuditQuery) -> Result<(Vec<AuditEvent>, usize), String> {
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let mut events = VecDeque::with_capacity(limit);
+        let mut total = 0;
+        // 记录一条匹配的事件
+        let mut keep = |event: AuditEvent| {
+            total += 1;
+            if events.len() == limit {
+                events.pop_front();
+            }
+            if limit > 0 {
+                events.push_back(event);
+            }
+        };
+        match &self.file {
+            Some((path, _)) => {
+                let file = tokio::fs::File::open(path)
+                    .await
+                    .map_err(|e| format!("读取审计日志失败: {}", e))?;
+                let mut lines = BufReader::new(file).lines();
+                while let Some(line) = lines
+                    .next_line()
+                    .await
+                    .map_err(|e| format!("读取审计日志失败: {}", e))?
+                {
+                    match serde_json::from_str::<AuditEvent>(&line) {
+                        Ok(event) if query.matches(&event) => keep(event),
+                        _ => {}
+                    }
+                }
+            }
+            None => {
+                let memory = self.memory.lock().unwrap();
+                for event in memory.iter().filter(|e| query.matches(e)) {
+                    keep(event.clone());
+                }
+            }
+        }
+        Ok((events.into(), total))
+    }
+}
@@ -1,35 +1,59 @@
 //! WebSocket 和 HTTP 处理器
 
-use crate::common::protocol::{TunnelConfig, WsMessage};
-use crate::manager::ServerState;
+use crate::admin::ApiIdentity;
+use crate::audit::{AuditAction, AuditEvent, AuditQuery};
+use crate::common::auth;
+use crate::common::codec::{Codec, Encoded};
+use crate::common::protocol::{
+    capability, ErrorCode, TunnelConfig, WsMessage, MIN_AUTH_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::common::queue;
+use crate::manager::{ClientSession, ServerState};
+use crate::tls::PeerCert;
+use crate::token::{self, Grant, NewToken};
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        Path, State, WebSocketUpgrade,
+        ConnectInfo, Path, Query, State, WebSocketUpgrade,
     },
     http::StatusCode,
-    response::{IntoResponse, Json},
+    response::{IntoResponse, Json, Response},
+    Extension,
 };
 use futures::{SinkExt, StreamExt};
 use serde::Deserialize;
 use serde_json::json;
-use tokio::sync::mpsc;
-use tracing::debug;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
 
 /// GET /status — 服务状态概览
 pub async fn get_status(State(state): State<ServerState>) -> impl IntoResponse {
     let clients = state.clients.len();
     let tunnels = state.tunnels.len();
     let connections = state.connections.len();
+    let limits = state.limiter.config();
     Json(json!({
         "code": 0,
         "message": "success",
         "data": {
             "status": "running",
             "version": env!("CARGO_PKG_VERSION"),
+            "protocol_version": PROTOCOL_VERSION,
+            "min_client_version": state.min_client_version,
             "clients": clients,
             "tunnels": tunnels,
-            "connections": connections
+            "connections": connections,
+            "auth": state.auth_enabled(),
+            "limits": {
+                "upgrades_per_minute": limits.upgrades_per_minute,
+                "max_auth_failures": limits.max_failures,
+                "failure_window_secs": limits.failure_window.as_secs(),
+                "ban_secs": limits.ban_duration.as_secs(),
+                "max_tunnels_per_client": state.max_tunnels_per_client,
+                "banned_ips": state.limiter.banned_count()
+            }
         }
     }))
 }
@@ -37,37 +61,54 @@ pub async fn get_status(State(state): State<ServerState>) -> impl IntoResponse {
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<ServerState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    peer: Option<Extension<PeerCert>>,
+) -> Response {
+    if let Err(e) = state.limiter.check_upgrade(addr.ip()) {
+        debug!("拒绝来自 {} 的连接: {}", addr, e);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({ "code": 429, "message": e, "data": null })),
+        )
+            .into_response();
+    }
+    let cert_cn = peer.and_then(|Extension(p)| p.common_name);
+    if state.require_client_cert && cert_cn.is_none() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "code": 403, "message": "需要客户端证书", "data": null })),
+        )
+            .into_response();
+    }
+    ws.on_upgrade(move |socket| handle_socket(socket, state, cert_cn, addr.ip()))
 }
 
-async fn handle_socket(socket: WebSocket, state: ServerState) {
+async fn handle_socket(socket: WebSocket, state: ServerState, cert_cn: Option<String>, ip: IpAddr) {
     let (mut ws_tx, mut ws_rx) = socket.split();
-    let (tx, mut rx) = mpsc::unbounded_channel::<WsMessage>();
+    let (tx, mut rx) = queue::channel();
     let mut client_id: Option<String> = None;
+    let mut rejected = false;
+    // 握手通过后记录 (客户端名称, 授权范围)
+    let mut authenticated: Option<(String, Grant)> = None;
 
-    // 发送任务 — Data 用 Binary 帧，其他用 Text/JSON
-    let send_task = tokio::spawn(async move {
+    // 握手挑战
+    let nonce = auth::generate_nonce();
+    let _ = tx.send(WsMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        nonce: nonce.clone(),
+    });
+
+    // 注册时按协商的能力切换帧格式
+    let codec = Arc::new(Codec::default());
+
+    // 发送任务 — 协商了 stream_frames 时 Data 用 Binary 帧，其他用 Text/JSON
+    let send_codec = Arc::clone(&codec);
+    let mut send_task = tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
-            let ws_msg = match &msg {
-                WsMessage::Data { conn_id, data } => {
-                    let mut buf = Vec::with_capacity(36 + data.len());
-                    let id_bytes = conn_id.as_bytes();
-                    if id_bytes.len() >= 36 {
-                        buf.extend_from_slice(&id_bytes[..36]);
-                    } else {
-                        buf.extend_from_slice(id_bytes);
-                        buf.resize(36, 0);
-                    }
-                    buf.extend_from_slice(data);
-                    Message::Binary(buf)
-                }
-                _ => {
-                    match serde_json::to_string(&msg) {
-                        Ok(t) => Message::Text(t),
-                        Err(_) => continue,
-                    }
-                }
+            let ws_msg = match send_codec.encode(&msg) {
+                Some(Encoded::Text(t)) => Message::Text(t),
+                Some(Encoded::Binary(b)) => Message::Binary(b),
+                None => continue,
             };
             if ws_tx.send(ws_msg).await.is_err() {
                 break;
@@ -76,29 +117,160 @@ async fn handle_socket(socket: WebSocket, state: ServerState) {
     });
 
     // 接收处理
-    while let Some(Ok(msg)) = ws_rx.next().await {
+    // remove_client 时通知本连接退出（管理员断开、同名顶替、Token 吊销）
+    let kicked = Arc::new(Notify::new());
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_rx.next() => msg,
+            _ = kicked.notified() => {
+                debug!("客户端已被服务端移除，关闭连接");
+                break;
+            }
+            // 连接断开或发送队列已满
+            _ = &mut send_task => {
+                debug!("发送任务已退出，关闭连接");
+                break;
+            }
+        };
+        let Some(Ok(msg)) = msg else {
+            break;
+        };
         match msg {
             Message::Text(text) => {
                 if let Ok(ws_msg) = serde_json::from_str::<WsMessage>(&text) {
                     match ws_msg {
-                        WsMessage::Register { client, tunnels } => {
-                            match state.register_client(client, tunnels, tx.clone()).await {
+                        WsMessage::Auth {
+                            protocol_version,
+                            client_name,
+                            ..
+                        } if protocol_version < MIN_AUTH_PROTOCOL_VERSION => {
+                            warn!(
+                                "客户端 {} 握手协议版本 {} 无效",
+                                client_name, protocol_version
+                            );
+                            let _ = tx.send(WsMessage::Error {
+                                code: ErrorCode::UnsupportedVersion,
+                                message: format!(
+                                    "不支持的协议版本 {} (需要 {}-{})",
+                                    protocol_version, MIN_AUTH_PROTOCOL_VERSION, PROTOCOL_VERSION
+                                ),
+                            });
+                            rejected = true;
+                            break;
+                        }
+                        WsMessage::Auth {
+                            client_name,
+                            mac,
+                            signed_claims,
+                            ..
+                        } => match state.authenticate(
+                            &nonce,
+                            &client_name,
+                            mac.as_deref(),
+                            signed_claims.as_deref(),
+                        ) {
+                            Ok(mut grant) => {
+                                grant.cert_cn = cert_cn.clone();
+                                authenticated = Some((client_name, grant));
+                            }
+                            Err((code, e)) => {
+                                warn!("客户端 {} ({}) 认证失败: {}", client_name, ip, e);
+                                state.audit.record(
+                                    AuditEvent::new(AuditAction::AuthFailure)
+                                        .ip(ip)
+                                        .client_name(&client_name)
+                                        .detail(&e),
+                                );
+                                state.limiter.record_failure(ip);
+                                let _ = tx.send(WsMessage::Error { code, message: e });
+                                rejected = true;
+                                break;
+                            }
+                        },
+                        WsMessage::Register {
+                            client,
+                            tunnels,
+                            protocol_version,
+                            capabilities,
+                        } => {
+                            let name = client.name.clone();
+                            let capabilities = capability::negotiate(capabilities.as_deref());
+                            codec.negotiated(&capabilities);
+                            let session = ClientSession {
+                                tx: tx.clone(),
+                                kick: Arc::clone(&kicked),
+                                ip,
+                                protocol_version,
+                                capabilities: capabilities.clone(),
+                            };
+                            let result = match (state.check_client_version(&client), authenticated.take()) {
+                                (Err(e), _) => Err(e),
+                                (Ok(()), Some((auth_name, _))) if auth_name != client.name => Err((
+                                    ErrorCode::AuthFailed,
+                                    "认证失败: 注册名称与握手名称不一致".to_string(),
+                                )),
+                                (Ok(()), Some((_, grant))) => state
+                                    .register_client(client, tunnels, grant, session)
+                                    .await
+                                    .map_err(|e| (ErrorCode::Forbidden, e)),
+                                // 旧版客户端不做握手，仅在未启用认证时放行
+                                (Ok(()), None) if !state.auth_enabled() => state
+                                    .register_client(
+                                        client,
+                                        tunnels,
+                                        Grant {
+                                            cert_cn: cert_cn.clone(),
+                                            ..Grant::default()
+                                        },
+                                        session,
+                                    )
+                                    .await
+                                    .map_err(|e| (ErrorCode::Forbidden, e)),
+                                (Ok(()), None) => Err((
+                                    ErrorCode::UnsupportedVersion,
+                                    format!(
+                                        "客户端版本过旧，不支持挑战-应答认证 (需要协议版本 {})，请升级 cec-tunnel",
+                                        PROTOCOL_VERSION
+                                    ),
+                                )),
+                            };
+                            match result {
                                 Ok((id, tunnel_infos)) => {
+                                    state.limiter.record_success(ip);
                                     client_id = Some(id.clone());
                                     let _ = tx.send(WsMessage::RegisterResponse {
                                         success: true,
                                         client_id: id,
                                         tunnels: tunnel_infos,
                                         message: None,
+                                        protocol_version: protocol_version.min(PROTOCOL_VERSION),
+                                        capabilities: Some(capabilities),
                                     });
                                 }
-                                Err(e) => {
+                                Err((code, e)) => {
+                                    warn!("客户端 {} ({}) 注册被拒绝: {}", name, ip, e);
+                                    state.audit.record(
+                                        AuditEvent::new(AuditAction::RegisterRejected)
+                                            .ip(ip)
+                                            .client_name(&name)
+                                            .detail(&e),
+                                    );
+                                    state.limiter.record_failure(ip);
+                                    let _ = tx.send(WsMessage::Error {
+                                        code,
+                                        message: e.clone(),
+                                    });
                                     let _ = tx.send(WsMessage::RegisterResponse {
                                         success: false,
                                         client_id: String::new(),
                                         tunnels: vec![],
                                         message: Some(e),
+                                        protocol_version: PROTOCOL_VERSION,
+                                        capabilities: None,
                                     });
+                                    rejected = true;
+                                    break;
                                 }
                             }
                         }
@@ -107,31 +279,115 @@ async fn handle_socket(socket: WebSocket, state: ServerState) {
                         }
                         WsMessage::ConnectionReady { tunnel_id, conn_id } => {
                             debug!("连接就绪: {} / {}", tunnel_id, conn_id);
+                            state.connection_ready(conn_id, client_id.as_deref());
+                        }
+                        WsMessage::ConnectionFailed {
+                            conn_id,
+                            reason,
+                            message,
+                        } => {
+                            state.connection_failed(
+                                conn_id,
+                                client_id.as_deref(),
+                                reason,
+                                &message,
+                            );
                         }
-                        WsMessage::Data { conn_id, data } => {
-                            if let Some(conn) = state.connections.get(&conn_id) {
-                                let _ = conn.tx.send(data);
+                        WsMessage::VisitorOpen {
+                            request_id,
+                            client_name,
+                            tunnel_name,
+                            mac,
+                        } => {
+                            // 被封禁的来源不能继续猜测私密隧道密钥
+                            if let Err(e) = state.limiter.check_banned(ip) {
+                                let _ = tx.send(WsMessage::Error {
+                                    code: ErrorCode::Forbidden,
+                                    message: e,
+                                });
+                                rejected = true;
+                                break;
                             }
+                            let result = match &client_id {
+                                Some(id) => state.open_visitor(
+                                    (id, ip, tx.clone()),
+                                    &nonce,
+                                    &request_id,
+                                    (&client_name, &tunnel_name),
+                                    mac.as_deref(),
+                                ),
+                                None => Err((ErrorCode::Forbidden, "未注册".to_string())),
+                            };
+                            if let Err((code, e)) = result {
+                                warn!("访问端连接 {}/{} 被拒绝: {}", client_name, tunnel_name, e);
+                                let _ = tx.send(WsMessage::VisitorAccept {
+                                    request_id,
+                                    conn_id: 0,
+                                    success: false,
+                                    message: Some(e.clone()),
+                                    compress: None,
+                                });
+                                // 缺少密钥计入失败次数，密钥错误由隧道客户端校验后计入
+                                if code == ErrorCode::AuthFailed {
+                                    let detail =
+                                        format!("访问端 {}/{}: {}", client_name, tunnel_name, e);
+                                    if state.visitor_auth_failed(client_id.clone(), ip, detail) {
+                                        rejected = true;
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                        msg @ (WsMessage::Data { .. }
+                        | WsMessage::WindowUpdate { .. }
+                        | WsMessage::HalfClose { .. }) => {
+                            handle_stream_frame(&state, client_id.as_deref(), msg);
+                        }
+                        WsMessage::CloseConnection { conn_id, reason } => {
+                            state.connection_closed(conn_id, client_id.as_deref(), reason);
                         }
-                        WsMessage::CloseConnection { conn_id } => {
-                            state.connections.remove(&conn_id);
+                        WsMessage::AddTunnelResponse {
+                            request_id,
+                            success: false,
+                            message,
+                            ..
+                        } => {
+                            // 客户端拒绝了下发的隧道 (如不在其 --allow-target 范围内)，释放服务端端口
+                            let owned = state
+                                .tunnels
+                                .get(&request_id)
+                                .is_some_and(|t| Some(&t.info.client_id) == client_id.as_ref());
+                            if owned {
+                                warn!(
+                                    "客户端拒绝隧道 {}: {}",
+                                    request_id,
+                                    message.as_deref().unwrap_or("")
+                                );
+                                if state.close_tunnel(&request_id).is_ok() {
+                                    let mut event = AuditEvent::new(AuditAction::CloseTunnel)
+                                        .ip(ip)
+                                        .tunnel_id(&request_id)
+                                        .detail(format!(
+                                            "客户端拒绝: {}",
+                                            message.as_deref().unwrap_or("")
+                                        ));
+                                    event.client_id = client_id.clone();
+                                    state.audit.record(event);
+                                }
+                            }
                         }
                         WsMessage::AddTunnelResponse { .. } => {
-                            // 不再需要处理：隧道由 HTTP API 直接创建
-                            debug!("忽略 AddTunnelResponse（隧道已由 HTTP API 创建）");
+                            // 隧道已由 HTTP API 创建，客户端确认后无需处理
+                            debug!("客户端已确认隧道");
                         }
                         _ => {}
                     }
                 }
             }
+            // Binary 消息: 紧凑二进制帧 (见 protocol::frame) 或旧版 conn_id 前缀格式，由 Codec 解析
             Message::Binary(data) => {
-                // Binary 帧: conn_id(36 bytes) + payload
-                if data.len() > 36 {
-                    let conn_id = String::from_utf8_lossy(&data[..36]).to_string();
-                    let payload = data[36..].to_vec();
-                    if let Some(conn) = state.connections.get(&conn_id) {
-                        let _ = conn.tx.send(payload);
-                    }
+                for msg in codec.decode_binary(&data) {
+                    handle_stream_frame(&state, client_id.as_deref(), msg);
                 }
             }
             Message::Close(_) => break,
@@ -143,7 +399,34 @@ async fn handle_socket(socket: WebSocket, state: ServerState) {
     if let Some(id) = client_id {
         state.remove_client(&id);
     }
-    send_task.abort();
+    if rejected {
+        // 等拒绝消息发出后再关闭连接
+        drop(tx);
+        if tokio::time::timeout(std::time::Duration::from_secs(2), &mut send_task)
+            .await
+            .is_err()
+        {
+            send_task.abort();
+        }
+    } else {
+        send_task.abort();
+    }
+}
+
+/// 连接数据、流量控制和半关闭消息，JSON 和二进制帧共用
+fn handle_stream_frame(state: &ServerState, client_id: Option<&str>, msg: WsMessage) {
+    match msg {
+        WsMessage::Data {
+            conn_id,
+            data,
+            compressed,
+        } => state.forward_data(conn_id, client_id, data, compressed),
+        WsMessage::WindowUpdate { conn_id, credit } => {
+            state.grant_window(conn_id, client_id, credit)
+        }
+        WsMessage::HalfClose { conn_id } => state.half_close(conn_id, client_id),
+        _ => {}
+    }
 }
 
 pub async fn list_clients(State(state): State<ServerState>) -> impl IntoResponse {
@@ -158,7 +441,10 @@ pub async fn list_clients(State(state): State<ServerState>) -> impl IntoResponse
                 "os": c.info.os,
                 "arch": c.info.arch,
                 "version": c.info.version,
+                "protocol_version": c.protocol_version,
+                "capabilities": c.capabilities,
                 "local_ip": c.info.local_ip,
+                "ip": c.ip,
                 "tunnels": c.tunnel_ids.len()
             })
         })
@@ -181,6 +467,22 @@ pub async fn list_tunnels(State(state): State<ServerState>) -> impl IntoResponse
             // 从原子计数器读取实时流量
             let bytes_sent = t.bytes_sent.load(std::sync::atomic::Ordering::Relaxed);
             let bytes_recv = t.bytes_recv.load(std::sync::atomic::Ordering::Relaxed);
+            let rejected = t.rejected.load(std::sync::atomic::Ordering::Relaxed);
+            // 压缩率为 WebSocket 上传输的字节数 / 压缩前的字节数
+            let compression = t.info.compress.map(|threshold| {
+                let (raw_bytes, wire_bytes) = t.compression.snapshot();
+                let ratio = if raw_bytes > 0 {
+                    wire_bytes as f64 / raw_bytes as f64
+                } else {
+                    1.0
+                };
+                json!({
+                    "threshold": threshold,
+                    "raw_bytes": raw_bytes,
+                    "wire_bytes": wire_bytes,
+                    "ratio": (ratio * 1000.0).round() / 1000.0
+                })
+            });
             json!({
                 "id": t.info.id,
                 "client_id": t.info.client_id,
@@ -193,6 +495,13 @@ pub async fn list_tunnels(State(state): State<ServerState>) -> impl IntoResponse
                 "state": t.info.state,
                 "bytes_sent": bytes_sent,
                 "bytes_recv": bytes_recv,
+                "allow": t.info.allow,
+                "deny": t.info.deny,
+                "rejected_connections": rejected,
+                "close_reasons": t.closes.snapshot(),
+                "compression": compression,
+                "e2e": t.info.e2e,
+                "secret": t.info.secret,
                 "created_at": t.info.created_at,
                 "last_active_at": t.info.last_active_at
             })
@@ -202,12 +511,35 @@ pub async fn list_tunnels(State(state): State<ServerState>) -> impl IntoResponse
     Json(json!({ "code": 0, "message": "success", "data": { "items": tunnels, "total": total } }))
 }
 
+/// 管理操作的审计事件，记录来源 IP 和 API Key 标识
+fn admin_event(
+    action: AuditAction,
+    addr: SocketAddr,
+    identity: Option<Extension<ApiIdentity>>,
+) -> AuditEvent {
+    AuditEvent::new(action)
+        .ip(addr.ip())
+        .api_key(identity.map(|Extension(id)| id.0))
+}
+
 pub async fn close_tunnel(
     State(state): State<ServerState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ApiIdentity>>,
     Path(tunnel_id): Path<String>,
 ) -> impl IntoResponse {
+    let owner = state
+        .tunnels
+        .get(&tunnel_id)
+        .map(|t| t.info.client_id.clone());
     match state.close_tunnel(&tunnel_id) {
-        Ok(_) => Json(json!({ "code": 0, "message": "success", "data": null })).into_response(),
+        Ok(_) => {
+            let mut event =
+                admin_event(AuditAction::CloseTunnel, addr, identity).tunnel_id(tunnel_id);
+            event.client_id = owner;
+            state.audit.record(event);
+            Json(json!({ "code": 0, "message": "success", "data": null })).into_response()
+        }
         Err(e) => (
             StatusCode::NOT_FOUND,
             Json(json!({ "code": 404, "message": e, "data": null })),
@@ -219,10 +551,18 @@ pub async fn close_tunnel(
 /// DELETE /api/clients/:id — 断开客户端连接
 pub async fn disconnect_client(
     State(state): State<ServerState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ApiIdentity>>,
     Path(client_id): Path<String>,
 ) -> impl IntoResponse {
-    if state.clients.contains_key(&client_id) {
+    let name = state.clients.get(&client_id).map(|c| c.info.name.clone());
+    if let Some(name) = name {
         state.remove_client(&client_id);
+        state.audit.record(
+            admin_event(AuditAction::DisconnectClient, addr, identity)
+                .client_id(client_id)
+                .client_name(name),
+        );
         Json(json!({ "code": 0, "message": "success", "data": null })).into_response()
     } else {
         (
@@ -246,11 +586,28 @@ pub struct AddTunnelRequest {
     pub server_port: Option<u16>,
     /// 隧道名称
     pub name: Option<String>,
+    /// 允许访问的来源 IP/CIDR
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// 拒绝访问的来源 IP/CIDR
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// 端到端加密，客户端需配置 --e2e-secret
+    #[serde(default)]
+    pub e2e: bool,
+    /// 私密隧道，不开放服务端端口，忽略 server_port；密钥为客户端 --secret-file 中的密钥，
+    /// 不经过服务端
+    #[serde(default)]
+    pub secret: bool,
+    /// 压缩阈值 (字节)，不小于该长度的数据压缩后传输
+    pub compress: Option<u32>,
 }
 
 /// POST /api/clients/:id/tunnels — 给已连接的客户端动态添加隧道
 pub async fn add_client_tunnel(
     State(state): State<ServerState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ApiIdentity>>,
     Path(client_id): Path<String>,
     Json(body): Json<AddTunnelRequest>,
 ) -> impl IntoResponse {
@@ -277,6 +634,12 @@ pub async fn add_client_tunnel(
         local_port: body.local_port,
         remote_port: body.server_port,
         name: body.name,
+        allow: body.allow,
+        deny: body.deny,
+        e2e: body.e2e,
+        secret: body.secret,
+        secret_key: None,
+        compress: body.compress,
     };
 
     // 服务端先创建隧道（绑定端口），再通知客户端记录映射
@@ -292,10 +655,25 @@ pub async fn add_client_tunnel(
                     tunnel_type: info.tunnel_type.clone(),
                     local_addr: info.local_addr.clone(),
                     local_port: info.local_port,
-                    remote_port: Some(info.server_port),
+                    remote_port: Some(info.server_port).filter(|_| !info.secret),
                     name: Some(info.name.clone()),
+                    allow: info.allow.clone(),
+                    deny: info.deny.clone(),
+                    e2e: info.e2e,
+                    secret: info.secret,
+                    secret_key: None,
+                    compress: info.compress,
                 },
             });
+            state.audit.record(
+                admin_event(AuditAction::AddTunnel, addr, identity)
+                    .client_id(client_id)
+                    .tunnel_id(&info.id)
+                    .detail(format!(
+                        "{}:{} -> 服务端端口 {}",
+                        info.local_addr, info.local_port, info.server_port
+                    )),
+            );
             Json(json!({ "code": 0, "message": "success", "data": info })).into_response()
         }
         Err(e) => (
@@ -305,3 +683,157 @@ pub async fn add_client_tunnel(
             .into_response(),
     }
 }
+
+fn token_json(state: &ServerState, t: &token::TokenEntry) -> serde_json::Value {
+    let clients = state
+        .clients
+        .iter()
+        .filter(|c| c.grant.token_id.as_deref() == Some(t.id.as_str()))
+        .count();
+    json!({
+        "id": t.id,
+        "name": t.name,
+        "token": token::mask_token(&t.token),
+        "client_pattern": t.entitlement.client_pattern,
+        "port_range": t.entitlement.port_range,
+        "tunnel_types": t.entitlement.tunnel_types,
+        "max_tunnels": t.entitlement.max_tunnels,
+        "created_at": t.created_at,
+        "expires_at": t.expires_at,
+        "revoked": t.revoked,
+        "active": t.is_active(),
+        "clients": clients
+    })
+}
+
+/// GET /api/tokens — 列出客户端 Token（Token 明文已隐藏）
+pub async fn list_tokens(State(state): State<ServerState>) -> impl IntoResponse {
+    let tokens: Vec<_> = state
+        .tokens
+        .list()
+        .iter()
+        .map(|t| token_json(&state, t))
+        .collect();
+    let total = tokens.len();
+    Json(json!({ "code": 0, "message": "success", "data": { "items": tokens, "total": total } }))
+}
+
+/// POST /api/tokens — 新建客户端 Token，响应中包含完整 Token，仅返回这一次
+pub async fn create_token(
+    State(state): State<ServerState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ApiIdentity>>,
+    Json(body): Json<NewToken>,
+) -> impl IntoResponse {
+    match state.tokens.create(body) {
+        Ok(t) => {
+            state.audit.record(
+                admin_event(AuditAction::CreateToken, addr, identity)
+                    .token_id(&t.id)
+                    .detail(&t.name),
+            );
+            let mut data = token_json(&state, &t);
+            data["token"] = json!(t.token);
+            Json(json!({ "code": 0, "message": "success", "data": data })).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "code": 400, "message": e, "data": null })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RevokeTokenQuery {
+    /// 同时断开使用该 Token 注册的客户端
+    #[serde(default)]
+    pub disconnect: bool,
+}
+
+/// DELETE /api/tokens/:id — 吊销 Token，`?disconnect=true` 时同时断开相关客户端
+pub async fn revoke_token(
+    State(state): State<ServerState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ApiIdentity>>,
+    Path(token_id): Path<String>,
+    Query(query): Query<RevokeTokenQuery>,
+) -> impl IntoResponse {
+    match state.tokens.revoke(&token_id) {
+        Ok(_) => {
+            let disconnected = if query.disconnect {
+                state.remove_clients_by_token(&token_id)
+            } else {
+                0
+            };
+            state.audit.record(
+                admin_event(AuditAction::RevokeToken, addr, identity)
+                    .token_id(&token_id)
+                    .detail(format!("断开 {} 个客户端", disconnected)),
+            );
+            Json(json!({ "code": 0, "message": "success", "data": { "disconnected": disconnected } }))
+                .into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "code": 400, "message": e, "data": null })),
+        )
+            .into_response(),
+    }
+}
+
+/// 请求体：设置 Token 过期时间
+#[derive(Deserialize, Default)]
+pub struct ExpireTokenRequest {
+    /// 过期时间 (RFC 3339)，不传则立即过期
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// POST /api/tokens/:id/expire — 设置 Token 过期时间
+pub async fn expire_token(
+    State(state): State<ServerState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ApiIdentity>>,
+    Path(token_id): Path<String>,
+    body: Option<Json<ExpireTokenRequest>>,
+) -> impl IntoResponse {
+    let expires_at = body
+        .and_then(|Json(b)| b.expires_at)
+        .unwrap_or_else(chrono::Utc::now);
+    match state.tokens.set_expiry(&token_id, expires_at) {
+        Ok(_) => {
+            state.audit.record(
+                admin_event(AuditAction::ExpireToken, addr, identity)
+                    .token_id(&token_id)
+                    .detail(format!("过期时间 {}", expires_at.to_rfc3339())),
+            );
+            Json(json!({ "code": 0, "message": "success", "data": { "expires_at": expires_at } }))
+                .into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "code": 400, "message": e, "data": null })),
+        )
+            .into_response(),
+    }
+}
+
+/// GET /api/audit — 查询审计日志，支持 since / until (RFC 3339)、action、client、limit 过滤
+pub async fn list_audit(
+    State(state): State<ServerState>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    match state.audit.query(&query).await {
+        Ok((items, total)) => Json(json!({
+            "code": 0,
+            "message": "success",
+            "data": { "items": items, "total": total }
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "code": 500, "message": e, "data": null })),
+        )
+            .into_response(),
+    }
+}
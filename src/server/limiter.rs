@@ -0,0 +1,338 @@
+//! /tunnel 端点与管理 API 按来源 IP 限流
+//!
+//! - 每个来源 IP 每分钟的 WebSocket 升级次数有上限，超出返回 429
+//! - 注册失败 (含认证失败、访问端密钥错误) 与管理 API Key 认证失败共用计数，
+//!   在统计窗口内达到上限后临时封禁该 IP
+
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const UPGRADE_WINDOW: Duration = Duration::from_secs(60);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    /// 每个来源 IP 每分钟允许的升级次数，0 表示不限制
+    pub upgrades_per_minute: u32,
+    /// 统计窗口内允许的注册失败次数，达到后封禁，0 表示不封禁
+    pub max_failures: u32,
+    pub failure_window: Duration,
+    pub ban_duration: Duration,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            upgrades_per_minute: 0,
+            max_failures: 0,
+            failure_window: Duration::from_secs(600),
+            ban_duration: Duration::from_secs(900),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    upgrade_window: Instant,
+    upgrades: u32,
+    failure_window: Instant,
+    failures: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Entry {
+    fn new(now: Instant) -> Self {
+        Self {
+            upgrade_window: now,
+            upgrades: 0,
+            failure_window: now,
+            failures: 0,
+            banned_until: None,
+        }
+    }
+
+    fn ban_remaining(&self, now: Instant) -> Option<Duration> {
+        self.banned_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+fn ban_message(remaining: Duration) -> String {
+    format!(
+        "认证失败次数过多，已被临时封禁，{} 秒后重试",
+        remaining.as_secs() + 1
+    )
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: LimitConfig,
+    entries: DashMap<IpAddr, Entry>,
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config,
+            entries: DashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &LimitConfig {
+        &self.config
+    }
+
+    /// 记录一次 WebSocket 升级，被封禁或超出频率时返回原因
+    pub fn check_upgrade(&self, ip: IpAddr) -> Result<(), String> {
+        self.check_upgrade_at(ip, Instant::now())
+    }
+
+    fn check_upgrade_at(&self, ip: IpAddr, now: Instant) -> Result<(), String> {
+        let ip = ip.to_canonical();
+        let mut entry = self.entries.entry(ip).or_insert_with(|| Entry::new(now));
+        if let Some(remaining) = entry.ban_remaining(now) {
+            return Err(ban_message(remaining));
+        }
+        if self.config.upgrades_per_minute == 0 {
+            return Ok(());
+        }
+        if now.duration_since(entry.upgrade_window) >= UPGRADE_WINDOW {
+            entry.upgrade_window = now;
+            entry.upgrades = 0;
+        }
+        if entry.upgrades >= self.config.upgrades_per_minute {
+            return Err(format!(
+                "连接过于频繁，每分钟最多 {} 次",
+                self.config.upgrades_per_minute
+            ));
+        }
+        entry.upgrades += 1;
+        Ok(())
+    }
+
+    /// 检查来源 IP 是否被封禁，不计入升级次数
+    pub fn check_banned(&self, ip: IpAddr) -> Result<(), String> {
+        self.check_banned_at(ip, Instant::now())
+    }
+
+    fn check_banned_at(&self, ip: IpAddr, now: Instant) -> Result<(), String> {
+        match self.entries.get(&ip.to_canonical()) {
+            Some(entry) => entry
+                .ban_remaining(now)
+                .map_or(Ok(()), |r| Err(ban_message(r))),
+            None => Ok(()),
+        }
+    }
+
+    /// 记录一次认证失败，达到上限时封禁该 IP 并返回 true
+    pub fn record_failure(&self, ip: IpAddr) -> bool {
+        self.record_failure_at(ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.config.max_failures == 0 {
+            return false;
+        }
+        let ip = ip.to_canonical();
+        let mut entry = self.entries.entry(ip).or_insert_with(|| Entry::new(now));
+        if now.duration_since(entry.failure_window) >= self.config.failure_window {
+            entry.failure_window = now;
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        if entry.failures < self.config.max_failures {
+            return false;
+        }
+        entry.failures = 0;
+        entry.banned_until = Some(now + self.config.ban_duration);
+        warn!(
+            "来源 {} 认证失败次数过多，封禁 {} 秒",
+            ip,
+            self.config.ban_duration.as_secs()
+        );
+        true
+    }
+
+    /// 注册成功后清零失败计数
+    pub fn record_success(&self, ip: IpAddr) {
+        if let Some(mut entry) = self.entries.get_mut(&ip.to_canonical()) {
+            entry.failures = 0;
+        }
+    }
+
+    /// 当前被封禁的 IP 数量
+    pub fn banned_count(&self) -> usize {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter(|e| e.ban_remaining(now).is_some())
+            .count()
+    }
+
+    /// 定期清理过期的记录，避免大量来源 IP 占用内存
+    pub fn spawn_cleanup(self: &Arc<Self>) {
+        let limiter = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                limiter.cleanup(Instant::now());
+            }
+        });
+    }
+
+    /// 移除既未封禁、也不在任何统计窗口内的记录
+    fn cleanup(&self, now: Instant) {
+        self.entries.retain(|_, e| {
+            e.ban_remaining(now).is_some()
+                || now.duration_since(e.upgrade_window) < UPGRADE_WINDOW
+                || (e.failures > 0
+                    && now.duration_since(e.failure_window) < self.config.failure_window)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn limiter(upgrades_per_minute: u32, max_failures: u32) -> RateLimiter {
+        RateLimiter::new(LimitConfig {
+            upgrades_per_minute,
+            max_failures,
+            failure_window: Duration::from_secs(10),
+            ban_duration: Duration::from_secs(30),
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn upgrade_window_resets() {
+        let limiter = limiter(2, 0);
+        let a = ip("192.0.2.1");
+        let t0 = Instant::now();
+        assert!(limiter.check_upgrade_at(a, t0).is_ok());
+        assert!(limiter.check_upgrade_at(a, t0 + SEC).is_ok());
+        assert!(limiter.check_upgrade_at(a, t0 + 2 * SEC).is_err());
+        // 其他 IP 各自计数
+        assert!(limiter
+            .check_upgrade_at(ip("192.0.2.2"), t0 + 2 * SEC)
+            .is_ok());
+        // 窗口结束后重新计数
+        assert!(limiter.check_upgrade_at(a, t0 + UPGRADE_WINDOW).is_ok());
+        assert!(limiter
+            .check_upgrade_at(a, t0 + UPGRADE_WINDOW + SEC)
+            .is_ok());
+        assert!(limiter
+            .check_upgrade_at(a, t0 + UPGRADE_WINDOW + 2 * SEC)
+            .is_err());
+    }
+
+    #[test]
+    fn zero_disables_limits() {
+        let limiter = limiter(0, 0);
+        let a = ip("192.0.2.1");
+        let t0 = Instant::now();
+        for i in 0..1000 {
+            assert!(limiter.check_upgrade_at(a, t0 + i * SEC / 1000).is_ok());
+            assert!(!limiter.record_failure_at(a, t0));
+        }
+        assert_eq!(limiter.banned_count(), 0);
+    }
+
+    #[test]
+    fn failures_ban_until_expiry() {
+        let limiter = limiter(0, 3);
+        let a = ip("192.0.2.1");
+        let t0 = Instant::now();
+        assert!(!limiter.record_failure_at(a, t0));
+        assert!(!limiter.record_failure_at(a, t0 + SEC));
+        assert!(limiter.record_failure_at(a, t0 + 2 * SEC));
+
+        // 封禁期间升级和管理 API 都被拒绝，IPv4 映射地址视为同一来源
+        let banned_at = t0 + 2 * SEC;
+        assert!(limiter.check_banned_at(a, banned_at + SEC).is_err());
+        assert!(limiter
+            .check_banned_at(ip("::ffff:192.0.2.1"), banned_at + SEC)
+            .is_err());
+        assert!(limiter.check_upgrade_at(a, banned_at + SEC).is_err());
+        assert!(limiter
+            .check_banned_at(ip("192.0.2.2"), banned_at + SEC)
+            .is_ok());
+
+        // 封禁到期后恢复，失败计数从零开始
+        let expired = banned_at + Duration::from_secs(30);
+        assert!(limiter.check_banned_at(a, expired).is_ok());
+        assert!(limiter.check_upgrade_at(a, expired).is_ok());
+        assert!(!limiter.record_failure_at(a, expired));
+    }
+
+    #[test]
+    fn failure_window_resets() {
+        let limiter = limiter(0, 3);
+        let a = ip("192.0.2.1");
+        let t0 = Instant::now();
+        assert!(!limiter.record_failure_at(a, t0));
+        assert!(!limiter.record_failure_at(a, t0 + SEC));
+        // 窗口结束后重新计数，不会封禁
+        let t1 = t0 + Duration::from_secs(10);
+        assert!(!limiter.record_failure_at(a, t1));
+        assert!(!limiter.record_failure_at(a, t1 + SEC));
+        assert!(limiter.record_failure_at(a, t1 + 2 * SEC));
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let limiter = limiter(0, 3);
+        let a = ip("192.0.2.1");
+        let t0 = Instant::now();
+        assert!(!limiter.record_failure_at(a, t0));
+        assert!(!limiter.record_failure_at(a, t0));
+        limiter.record_success(a);
+        assert!(!limiter.record_failure_at(a, t0));
+        assert!(!limiter.record_failure_at(a, t0));
+        assert!(limiter.record_failure_at(a, t0));
+    }
+
+    #[test]
+    fn cleanup_keeps_active_entries() {
+        let limiter = RateLimiter::new(LimitConfig {
+            upgrades_per_minute: 10,
+            max_failures: 2,
+            failure_window: Duration::from_secs(120),
+            ban_duration: Duration::from_secs(300),
+        });
+        let t0 = Instant::now();
+        let (upgraded, failed, banned) = (ip("192.0.2.1"), ip("192.0.2.2"), ip("192.0.2.3"));
+        limiter.check_upgrade_at(upgraded, t0).unwrap();
+        limiter.record_failure_at(failed, t0);
+        limiter.record_failure_at(banned, t0);
+        limiter.record_failure_at(banned, t0);
+
+        limiter.cleanup(t0 + SEC);
+        assert_eq!(limiter.entries.len(), 3);
+
+        // 升级窗口已过，但失败统计窗口和封禁仍有效
+        limiter.cleanup(t0 + UPGRADE_WINDOW);
+        assert!(limiter.entries.contains_key(&failed));
+        assert!(limiter.entries.contains_key(&banned));
+        assert!(!limiter.entries.contains_key(&upgraded));
+
+        // 失败统计窗口已过，封禁仍有效
+        limiter.cleanup(t0 + Duration::from_secs(120));
+        assert!(!limiter.entries.contains_key(&failed));
+        assert!(limiter.entries.contains_key(&banned));
+
+        limiter.cleanup(t0 + Duration::from_secs(300));
+        assert!(limiter.entries.is_empty());
+    }
+}
@@ -4,27 +4,39 @@
 //! - 9998: ws:// (明文 WebSocket)
 //! - 9999: wss:// (TLS 加密 WebSocket)
 
+mod acme;
+mod admin;
+mod audit;
 mod handler;
+mod limiter;
 mod manager;
+mod signed_token;
+mod tls;
+mod token;
 
 #[path = "../common/mod.rs"]
 mod common;
 
 use anyhow::Result;
 use axum::{
+    middleware,
     routing::{delete, get, post},
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::{info, warn};
 
 #[derive(Parser, Debug)]
 #[command(name = "cec-tunnel-server")]
 #[command(version, about = "CEC Tunnel Server - 内网穿透服务端")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// 监听地址
     #[arg(short, long, default_value = "0.0.0.0")]
     bind: String,
@@ -53,6 +65,23 @@ struct Args {
     #[arg(long)]
     token: Option<String>,
 
+    /// Token 注册表文件 (JSON)，为每个 Token 配置客户端名称、端口范围、隧道类型和数量限制；
+    /// 通过 /api/tokens 管理的 Token 也保存在此文件中
+    #[arg(long)]
+    token_file: Option<String>,
+
+    /// 签名 Token 的密钥文件，配置后接受 mint-token 签发的自包含 Token
+    #[arg(long)]
+    token_signing_key: Option<String>,
+
+    /// 管理 API Key，格式 [read|operator|admin:]key，未指定角色时为 admin，可重复指定多个 (未指定时管理 API 不做认证)
+    #[arg(long = "api-key")]
+    api_keys: Vec<String>,
+
+    /// 允许跨域访问管理 API 的来源，可重复指定，`*` 表示任意来源 (未指定时不返回 CORS 头)
+    #[arg(long = "cors-origin")]
+    cors_origins: Vec<String>,
+
     /// TLS 证书文件路径 (PEM 格式)
     #[arg(long, default_value = "/etc/cec-tunnel/cert.pem")]
     tls_cert: String,
@@ -61,6 +90,80 @@ struct Args {
     #[arg(long, default_value = "/etc/cec-tunnel/key.pem")]
     tls_key: String,
 
+    /// 客户端 CA 证书 (PEM)，配置后 /tunnel 只接受携带该 CA 签发证书的 wss:// 客户端，
+    /// 且证书 CN 必须与客户端名称一致
+    #[arg(long)]
+    client_ca: Option<String>,
+
+    /// 证书文件变更检查间隔 (秒)，检测到变更时自动重新加载，0 表示仅在收到 SIGHUP 时重新加载
+    #[arg(long, default_value = "30")]
+    tls_reload_interval: u64,
+
+    /// 通过 ACME 自动签发 wss:// 证书的域名，可重复指定 (配置后忽略 --tls-cert/--tls-key)
+    #[arg(long = "acme-domain")]
+    acme_domains: Vec<String>,
+
+    /// ACME 账户联系邮箱
+    #[arg(long)]
+    acme_email: Option<String>,
+
+    /// ACME 目录 URL (默认 Let's Encrypt，测试时可指向 Pebble，如 https://localhost:14000/dir)
+    #[arg(long, default_value = acme::LETS_ENCRYPT_DIRECTORY)]
+    acme_directory: String,
+
+    /// ACME 账户和证书缓存目录
+    #[arg(long, default_value = "/etc/cec-tunnel/acme")]
+    acme_cache: String,
+
+    /// ACME 验证方式。http-01 (默认) 需要 --acme-http-port 对外为 80；
+    /// tls-alpn-01 在 wss 端口上验证，公共 ACME CA 只连接 443 端口，--wss-port 不是 443
+    /// 且未做端口转发时无法签发
+    #[arg(long, value_enum, default_value = "http-01")]
+    acme_challenge: acme::ChallengeType,
+
+    /// HTTP-01 验证监听端口，公共 ACME CA 只连接 80 端口
+    #[arg(long, default_value = "80")]
+    acme_http_port: u16,
+
+    /// 额外信任的 ACME 服务器 CA (PEM)，如 Pebble 的 pebble.minica.pem
+    #[arg(long)]
+    acme_ca: Option<String>,
+
+    /// 每个来源 IP 每分钟允许的 /tunnel 连接次数，0 表示不限制。
+    /// 来源 IP 取 TCP 对端地址，不解析 X-Forwarded-For：服务端前有反向代理时所有客户端共用代理地址的
+    /// 计数和封禁，应调大或关闭限制 (同时考虑 --max-auth-failures)，改在代理上限流
+    #[arg(long, default_value = "60")]
+    rate_limit: u32,
+
+    /// 来源 IP 在 --ban-window 内注册失败 (含认证失败) 达到该次数后临时封禁，0 表示不封禁。
+    /// 反向代理后所有客户端同一来源，个别客户端认证失败会导致全部被封禁
+    #[arg(long, default_value = "5")]
+    max_auth_failures: u32,
+
+    /// 统计注册失败次数的时间窗口 (秒)
+    #[arg(long, default_value = "600")]
+    ban_window: u64,
+
+    /// 临时封禁时长 (秒)
+    #[arg(long, default_value = "900")]
+    ban_duration: u64,
+
+    /// 每个客户端的最大隧道数，0 表示不限制
+    #[arg(long, default_value = "100")]
+    max_tunnels_per_client: usize,
+
+    /// 允许注册的最低客户端版本 (如 0.3.0)，低于该版本的客户端被拒绝并提示升级
+    #[arg(long)]
+    min_client_version: Option<String>,
+
+    /// 等待隧道客户端连上本地服务的超时 (秒)，超时后关闭外部连接或回复访问端失败
+    #[arg(long, default_value = "10")]
+    ready_timeout: u64,
+
+    /// 审计日志文件 (JSON Lines，追加写入)，记录管理操作和注册事件；未指定时仅在内存保留最近 1000 条
+    #[arg(long)]
+    audit_log: Option<String>,
+
     /// 启用 ws:// 明文端口
     #[arg(long)]
     enable_ws: bool,
@@ -74,19 +177,138 @@ struct Args {
     log_level: String,
 }
 
-fn build_router(state: manager::ServerState) -> Router {
-    Router::new()
-        .route("/", get(|| async { "CEC Tunnel Server" }))
-        .route("/health", get(|| async { "OK" }))
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 签发自包含签名 Token（离线校验，无需 Token 数据库）
+    MintToken {
+        /// 签名密钥文件，与服务端 --token-signing-key 相同
+        #[arg(long)]
+        signing_key: String,
+
+        /// 允许的客户端名称，支持 `*` 通配
+        #[arg(long)]
+        name: String,
+
+        /// 有效期，如 30d / 12h / 90m / 3600s
+        #[arg(long, default_value = "30d")]
+        expires_in: String,
+
+        /// 允许的服务端端口范围，如 10000-10099
+        #[arg(long)]
+        ports: Option<String>,
+
+        /// 允许的隧道类型，逗号分隔，如 tcp,udp
+        #[arg(long, value_delimiter = ',')]
+        types: Vec<String>,
+
+        /// 最大隧道数
+        #[arg(long)]
+        max_tunnels: Option<usize>,
+    },
+}
+
+/// 执行 mint-token 子命令，Token 输出到标准输出
+fn mint_token(command: Command) -> Result<()> {
+    let Command::MintToken {
+        signing_key,
+        name,
+        expires_in,
+        ports,
+        types,
+        max_tunnels,
+    } = command;
+
+    let key = signed_token::SigningKey::load(&signing_key)?;
+    let ttl = signed_token::parse_duration(&expires_in)
+        .filter(|&ttl| ttl > 0)
+        .ok_or_else(|| anyhow::anyhow!("无效的有效期: {}，须为正数", expires_in))?;
+    let port_range = match ports {
+        Some(p) => {
+            let (start, end) = p
+                .split_once('-')
+                .ok_or_else(|| anyhow::anyhow!("无效的端口范围: {}", p))?;
+            Some((start.parse()?, end.parse()?))
+        }
+        None => None,
+    };
+    let tunnel_types = if types.is_empty() {
+        None
+    } else {
+        Some(
+            types
+                .iter()
+                .map(|t| t.parse().map_err(anyhow::Error::msg))
+                .collect::<Result<Vec<_>>>()?,
+        )
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let exp = now
+        .checked_add(ttl)
+        .filter(|&exp| chrono::DateTime::from_timestamp(exp, 0).is_some())
+        .ok_or_else(|| anyhow::anyhow!("有效期过长: {}", expires_in))?;
+    let claims = signed_token::Claims {
+        iat: now,
+        exp,
+        entitlement: token::Entitlement {
+            client_pattern: Some(name),
+            port_range,
+            tunnel_types,
+            max_tunnels,
+        },
+    };
+    println!("{}", key.mint(&claims)?);
+    Ok(())
+}
+
+fn build_router(
+    state: manager::ServerState,
+    api_keys: Arc<admin::ApiKeys>,
+    cors_origins: &[String],
+) -> Router {
+    // 管理 API 按角色鉴权：只读接口需要 read，添加/关闭隧道、Token 和审计日志查询需要 operator，
+    // 断开客户端和变更 Token 需要 admin
+    let require = |role| admin::RequireRole::new(&api_keys, role, &state.audit, &state.limiter);
+    let read = require(admin::Role::Read);
+    let operator = require(admin::Role::Operator);
+    let admin = require(admin::Role::Admin);
+
+    let read_api = Router::new()
         .route("/status", get(handler::get_status))
-        .route("/tunnel", get(handler::ws_handler))
         .route("/api/clients", get(handler::list_clients))
-        .route("/api/clients/:id", delete(handler::disconnect_client))
         .route("/api/tunnels", get(handler::list_tunnels))
+        .route_layer(middleware::from_fn_with_state(read, admin::require_role));
+
+    let operator_api = Router::new()
         .route("/api/tunnels/:id", delete(handler::close_tunnel))
         .route("/api/clients/:id/tunnels", post(handler::add_client_tunnel))
-        .layer(CorsLayer::permissive())
-        .with_state(state)
+        .route("/api/tokens", get(handler::list_tokens))
+        .route("/api/audit", get(handler::list_audit))
+        .route_layer(middleware::from_fn_with_state(
+            operator,
+            admin::require_role,
+        ));
+
+    let admin_api = Router::new()
+        .route("/api/clients/:id", delete(handler::disconnect_client))
+        .route("/api/tokens", post(handler::create_token))
+        .route("/api/tokens/:id", delete(handler::revoke_token))
+        .route("/api/tokens/:id/expire", post(handler::expire_token))
+        .route_layer(middleware::from_fn_with_state(admin, admin::require_role));
+
+    let router = Router::new()
+        .route("/", get(|| async { "CEC Tunnel Server" }))
+        .route("/health", get(|| async { "OK" }))
+        .route("/tunnel", get(handler::ws_handler))
+        .merge(read_api)
+        .merge(operator_api)
+        .merge(admin_api);
+
+    let router = match admin::cors_layer(cors_origins) {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+    router.with_state(state)
 }
 
 #[tokio::main]
@@ -98,6 +320,10 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
+    if let Some(command) = args.command {
+        return mint_token(command);
+    }
+
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::from_default_env()
@@ -111,8 +337,61 @@ async fn main() -> Result<()> {
     let ws_port = args.port.unwrap_or(args.ws_port);
     info!("端口范围: {} - {}", args.port_start, args.port_end);
 
-    let state = manager::ServerState::new(args.port_start, args.port_end, args.token);
-    let app = build_router(state);
+    let tokens = token::TokenRegistry::load(args.token, args.token_file.as_deref())?;
+    if tokens.is_enabled() {
+        info!("Token 认证已启用 ({} 个 Token)", tokens.len());
+    }
+    let signing_key = match &args.token_signing_key {
+        Some(path) => {
+            info!("签名 Token 认证已启用 ({})", path);
+            Some(signed_token::SigningKey::load(path)?)
+        }
+        None => None,
+    };
+    if tokens.is_enabled() && tokens.len() == 0 && signing_key.is_none() {
+        warn!(
+            "Token 文件 {} 不存在或为空，通过 /api/tokens 创建 Token 之前所有客户端都将被拒绝",
+            args.token_file.as_deref().unwrap_or_default()
+        );
+    }
+    let mut state = manager::ServerState::new(args.port_start, args.port_end, tokens, signing_key);
+    if let Some(ca) = &args.client_ca {
+        info!("客户端证书认证已启用 (CA: {})", ca);
+        if args.enable_ws {
+            warn!("已配置 --client-ca，ws:// 端口上的客户端将无法注册");
+        }
+        state.require_client_cert = true;
+    }
+    state.limiter = Arc::new(limiter::RateLimiter::new(limiter::LimitConfig {
+        upgrades_per_minute: args.rate_limit,
+        max_failures: args.max_auth_failures,
+        failure_window: Duration::from_secs(args.ban_window),
+        ban_duration: Duration::from_secs(args.ban_duration),
+    }));
+    state.limiter.spawn_cleanup();
+    state.max_tunnels_per_client = args.max_tunnels_per_client;
+    state.ready_timeout = Duration::from_secs(args.ready_timeout);
+    if let Some(version) = &args.min_client_version {
+        if manager::parse_version(version).is_none() {
+            anyhow::bail!(
+                "无效的 --min-client-version: {}，格式为 主版本.次版本[.修订号]",
+                version
+            );
+        }
+        info!("最低客户端版本: {}", version);
+        state.min_client_version = Some(version.clone());
+    }
+    if let Some(path) = &args.audit_log {
+        info!("审计日志: {}", path);
+        state.audit = Arc::new(audit::AuditLog::open(path)?);
+    }
+    let api_keys = Arc::new(admin::ApiKeys::parse(&args.api_keys)?);
+    if api_keys.is_enabled() {
+        info!("管理 API 认证已启用 ({} 个 API Key)", api_keys.len());
+    } else {
+        warn!("未配置 --api-key，管理 API 无需认证即可访问");
+    }
+    let app = build_router(state, api_keys, &args.cors_origins);
 
     if !args.enable_ws && !args.enable_wss {
         eprintln!("错误: ws 和 wss 都未启用，至少需要启用一个");
@@ -126,7 +405,12 @@ async fn main() -> Result<()> {
         info!("ws://  -> {}", ws_addr);
         Some(tokio::spawn(async move {
             let listener = tokio::net::TcpListener::bind(ws_addr).await.unwrap();
-            axum::serve(listener, ws_app).await.unwrap();
+            axum::serve(
+                listener,
+                ws_app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
         }))
     } else {
         info!("ws:// 端口 {} 未启用 (--enable-ws 未设置)", ws_port);
@@ -139,13 +423,68 @@ async fn main() -> Result<()> {
         let has_tls = tokio::fs::metadata(&args.tls_cert).await.is_ok()
             && tokio::fs::metadata(&args.tls_key).await.is_ok();
 
-        if has_tls {
-            let config = RustlsConfig::from_pem_file(&args.tls_cert, &args.tls_key).await?;
+        if !args.acme_domains.is_empty() {
+            let acme = Arc::new(acme::Acme::new(acme::AcmeConfig {
+                domains: args.acme_domains.clone(),
+                contact: args.acme_email.clone(),
+                directory: args.acme_directory.clone(),
+                cache_dir: args.acme_cache.clone().into(),
+                challenge: args.acme_challenge,
+                ca_file: args.acme_ca.clone(),
+            })?);
+            let config = RustlsConfig::from_config(Arc::new(tls::acme_server_config(
+                acme.resolver(),
+                args.client_ca.as_deref(),
+            )?));
+            if args.acme_challenge == acme::ChallengeType::TlsAlpn01 && args.wss_port != 443 {
+                warn!(
+                    "ACME 使用 tls-alpn-01 验证，但 wss 端口为 {}：公共 ACME CA 只连接 443 端口，\
+                     除非外部 443 端口转发到该端口，否则无法签发证书 (可改用 --acme-challenge http-01)",
+                    args.wss_port
+                );
+            }
+            if args.acme_challenge == acme::ChallengeType::Http01 {
+                let http_addr: SocketAddr =
+                    format!("{}:{}", args.bind, args.acme_http_port).parse()?;
+                let listener = tokio::net::TcpListener::bind(http_addr).await?;
+                let http_app = acme.http_challenge_router();
+                info!("ACME HTTP-01 验证 -> {}", http_addr);
+                tokio::spawn(async move {
+                    axum::serve(listener, http_app).await.unwrap();
+                });
+            }
+            Arc::clone(&acme).spawn();
+            info!(
+                "wss:// -> {} (ACME: {})",
+                wss_addr,
+                args.acme_domains.join(", ")
+            );
+            let wss_app = app;
+            Some(tokio::spawn(async move {
+                axum_server::bind(wss_addr)
+                    .acceptor(tls::ClientCertAcceptor::new(config))
+                    .serve(wss_app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await
+                    .unwrap();
+            }))
+        } else if has_tls {
+            let files = tls::TlsFiles {
+                cert: args.tls_cert.clone(),
+                key: args.tls_key.clone(),
+                client_ca: args.client_ca.clone(),
+            };
+            let config = files.load()?;
+            tls::spawn_reloader(
+                files,
+                config.clone(),
+                Duration::from_secs(args.tls_reload_interval),
+            );
             info!("wss:// -> {} (TLS: {}, {})", wss_addr, args.tls_cert, args.tls_key);
             let wss_app = app;
             Some(tokio::spawn(async move {
-                axum_server::bind_rustls(wss_addr, config)
-                    .serve(wss_app.into_make_service())
+                axum_server::bind(wss_addr)
+                    .acceptor(tls::ClientCertAcceptor::new(config))
+                    .serve(wss_app.into_make_service_with_connect_info::<SocketAddr>())
                     .await
                     .unwrap();
             }))
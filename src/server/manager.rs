@@ -1,12 +1,27 @@
 //! 隧道管理器
 
-use crate::common::protocol::{ClientInfo, TunnelConfig, TunnelInfo, WsMessage};
+use crate::audit::{AuditAction, AuditEvent, AuditLog};
+use crate::common::compress;
+use crate::common::flow::{self, Window};
+use crate::common::half_close;
+use crate::common::ip_filter::IpFilter;
+use crate::common::protocol::{
+    capability, ClientInfo, CloseReason, ErrorCode, StreamId, TunnelConfig, TunnelInfo,
+    VisitorAuth, WsMessage, PROTOCOL_VERSION,
+};
+use crate::common::queue::SendQueue;
+use crate::limiter::RateLimiter;
+use crate::signed_token::SigningKey;
+use crate::token::{Grant, TokenRegistry};
 use dashmap::DashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, Notify};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
@@ -14,19 +29,61 @@ use uuid::Uuid;
 pub struct ServerState {
     pub clients: Arc<DashMap<String, ClientState>>,
     pub tunnels: Arc<DashMap<String, TunnelState>>,
-    pub connections: Arc<DashMap<String, ConnectionState>>,
+    pub connections: Arc<DashMap<StreamId, ConnectionState>>,
+    /// 等待隧道客户端连上本地服务的访问端连接: 隧道侧流 ID -> 访问端
+    pending_visitors: Arc<DashMap<StreamId, PendingVisitor>>,
     pub port_start: u16,
     pub port_end: u16,
-    #[allow(dead_code)]
-    pub auth_token: Option<String>,
+    pub tokens: Arc<TokenRegistry>,
+    pub signing_key: Option<Arc<SigningKey>>,
+    /// 配置了 --client-ca 时，/tunnel 只接受携带客户端证书的 wss:// 连接
+    pub require_client_cert: bool,
+    /// /tunnel 按来源 IP 限流和封禁
+    pub limiter: Arc<RateLimiter>,
+    /// 每个客户端的最大隧道数，0 表示不限制 (与 Token 的 max_tunnels 同时生效)
+    pub max_tunnels_per_client: usize,
+    pub audit: Arc<AuditLog>,
+    /// 允许注册的最低客户端版本 (如 0.3.0)
+    pub min_client_version: Option<String>,
+    /// 等待隧道客户端连上本地服务 (ConnectionReady) 的超时
+    pub ready_timeout: Duration,
     next_client_id: Arc<AtomicU64>,
+    next_stream_id: Arc<AtomicU32>,
 }
 
 pub struct ClientState {
     pub info: ClientInfo,
-    #[allow(dead_code)] // 预留：服务端主动推送
-    pub tx: mpsc::UnboundedSender<WsMessage>,
+    pub tx: SendQueue,
     pub tunnel_ids: Vec<String>,
+    /// 注册时所用 Token 及其授权范围
+    pub grant: Grant,
+    /// 来源 IP
+    pub ip: IpAddr,
+    /// 通知 WebSocket 会话关闭
+    pub kick: Arc<Notify>,
+    /// 协商后的协议版本
+    pub protocol_version: u32,
+    /// 协商后的能力
+    pub capabilities: Vec<String>,
+}
+
+impl ClientState {
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// 客户端 WebSocket 会话，注册时与客户端绑定
+pub struct ClientSession {
+    pub tx: SendQueue,
+    /// 通知 WebSocket 会话关闭
+    pub kick: Arc<Notify>,
+    /// 来源 IP
+    pub ip: IpAddr,
+    /// 客户端声明的协议版本
+    pub protocol_version: u32,
+    /// 协商后的能力
+    pub capabilities: Vec<String>,
 }
 
 pub struct TunnelState {
@@ -34,25 +91,162 @@ pub struct TunnelState {
     pub shutdown: Option<tokio::sync::broadcast::Sender<()>>,
     pub bytes_sent: Arc<AtomicU64>,
     pub bytes_recv: Arc<AtomicU64>,
+    /// 被 allow/deny 规则拒绝的连接数
+    pub rejected: Arc<AtomicU64>,
+    /// 按关闭原因统计的连接数
+    pub closes: Arc<CloseStats>,
+    /// 经 WebSocket 收发的数据量，用于计算压缩率
+    pub compression: Arc<CompressStats>,
 }
 
+/// 按关闭原因统计隧道的连接数，每条连接只在首先关闭的一端计数一次
+#[derive(Debug, Default)]
+pub struct CloseStats(DashMap<CloseReason, u64>);
+
+impl CloseStats {
+    pub fn record(&self, reason: CloseReason) {
+        // 另一端关闭的转告，已在另一端计数
+        if reason != CloseReason::PeerClosed {
+            *self.0.entry(reason).or_default() += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<CloseReason, u64> {
+        self.0.iter().map(|e| (*e.key(), *e.value())).collect()
+    }
+}
+
+/// 隧道经 WebSocket 收发的 Data 数据量，包括未压缩的数据
+#[derive(Debug, Default)]
+pub struct CompressStats {
+    /// 压缩前 (解压后) 的字节数
+    raw: AtomicU64,
+    /// WebSocket 上实际传输的字节数
+    wire: AtomicU64,
+}
+
+impl CompressStats {
+    pub fn record(&self, raw: usize, wire: usize) {
+        self.raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.wire.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    /// (压缩前字节数, 传输字节数)
+    pub fn snapshot(&self) -> (u64, u64) {
+        (
+            self.raw.load(Ordering::Relaxed),
+            self.wire.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// 按阈值压缩发往客户端的数据并计数，threshold 为 None 时不压缩
+fn compress_data(
+    conn_id: StreamId,
+    data: Vec<u8>,
+    threshold: Option<u32>,
+    stats: &CompressStats,
+) -> WsMessage {
+    let raw = data.len();
+    let (data, compressed) = match threshold.and_then(|t| compress::compress(&data, t)) {
+        Some(compressed) => (compressed, true),
+        None => (data, false),
+    };
+    stats.record(raw, data.len());
+    WsMessage::Data {
+        conn_id,
+        data,
+        compressed,
+    }
+}
+
+/// 等待 ConnectionReady 的访问端连接
+struct PendingVisitor {
+    tx: SendQueue,
+    /// 访问端的客户端 ID 和来源 IP，密钥错误时计入认证失败
+    client_id: String,
+    ip: IpAddr,
+    request_id: String,
+    /// 访问端侧的流 ID
+    conn_id: StreamId,
+    /// 回复访问端的压缩阈值，访问端未协商 `deflate` 时为 None
+    compress: Option<u32>,
+}
+
+type Tx = SendQueue;
+
 pub struct ConnectionState {
     #[allow(dead_code)] // 预留：连接追踪
     pub tunnel_id: String,
     pub client_id: String,
-    pub tx: mpsc::UnboundedSender<Vec<u8>>,
+    /// 发往本地连接的数据，积压量受 window 限制，空数据表示对端已关闭写方向
+    pub tx: mpsc::Sender<Vec<u8>>,
+    pub window: Arc<Window>,
+    /// 客户端连上本地服务时通知，外部连接在此之前不读取
+    pub ready: Option<oneshot::Sender<()>>,
+    /// 所属隧道的关闭原因统计
+    pub closes: Arc<CloseStats>,
+    /// 所属隧道的压缩统计
+    pub compression: Arc<CompressStats>,
+}
+
+impl Drop for ConnectionState {
+    fn drop(&mut self) {
+        // 唤醒等待额度的发送方，连接移除后额度不会再归还
+        self.window.close();
+    }
 }
 
 impl ServerState {
-    pub fn new(port_start: u16, port_end: u16, auth_token: Option<String>) -> Self {
+    pub fn new(
+        port_start: u16,
+        port_end: u16,
+        tokens: TokenRegistry,
+        signing_key: Option<SigningKey>,
+    ) -> Self {
         Self {
             clients: Arc::new(DashMap::new()),
             tunnels: Arc::new(DashMap::new()),
             connections: Arc::new(DashMap::new()),
+            pending_visitors: Arc::new(DashMap::new()),
             port_start,
             port_end,
-            auth_token,
+            tokens: Arc::new(tokens),
+            signing_key: signing_key.map(Arc::new),
+            require_client_cert: false,
+            limiter: Arc::new(RateLimiter::default()),
+            max_tunnels_per_client: 0,
+            audit: Arc::new(AuditLog::default()),
+            min_client_version: None,
+            ready_timeout: Duration::from_secs(10),
             next_client_id: Arc::new(AtomicU64::new(1)),
+            next_stream_id: Arc::new(AtomicU32::new(1)),
+        }
+    }
+
+    /// 是否启用了客户端认证（Token 注册表或签名密钥）
+    pub fn auth_enabled(&self) -> bool {
+        self.tokens.is_enabled() || self.signing_key.is_some()
+    }
+
+    /// 校验握手应答，返回对应 Token 的授权结果，失败时返回 (错误码, 原因)
+    pub fn authenticate(
+        &self,
+        nonce: &str,
+        client_name: &str,
+        mac: Option<&str>,
+        signed_claims: Option<&str>,
+    ) -> Result<Grant, (ErrorCode, String)> {
+        match (signed_claims, &self.signing_key) {
+            (Some(unsigned), Some(key)) => key.authorize(unsigned, nonce, client_name, mac),
+            (Some(_), None) => Err((
+                ErrorCode::AuthFailed,
+                "认证失败: 服务端未配置签名密钥，不接受签名 Token".to_string(),
+            )),
+            (None, _) if !self.tokens.is_enabled() && self.signing_key.is_some() => {
+                Err((ErrorCode::AuthFailed, "认证失败: 缺少 Token".to_string()))
+            }
+            (None, _) => self.tokens.authorize(nonce, client_name, mac),
         }
     }
 
@@ -60,20 +254,55 @@ impl ServerState {
         &self,
         client: ClientInfo,
         tunnels: Vec<TunnelConfig>,
-        tx: mpsc::UnboundedSender<WsMessage>,
+        grant: Grant,
+        session: ClientSession,
     ) -> Result<(String, Vec<TunnelInfo>), String> {
+        let ClientSession {
+            tx,
+            kick,
+            ip,
+            protocol_version,
+            capabilities,
+        } = session;
+
+        // 客户端证书 CN 与注册名称绑定
+        if let Some(cn) = &grant.cert_cn {
+            if cn != &client.name {
+                return Err(format!(
+                    "客户端名称 {} 与证书 CN {} 不一致",
+                    client.name, cn
+                ));
+            }
+        }
+
+        // 先校验授权范围，任何一项不满足都不绑定端口
+        let entitlement = &grant.entitlement;
+        entitlement.check_client_name(&client.name)?;
+        for (i, config) in tunnels.iter().enumerate() {
+            self.check_tunnel_cap(i)?;
+            entitlement.check_tunnel(config, i)?;
+        }
+        let port_range = entitlement.port_range(self.port_start, self.port_end);
+
         // 用客户端名称做去重，自增数字做 ID
         let client_id = if !client.name.is_empty() {
             // 同名客户端去重：清理旧的同名客户端及其隧道
-            let old_ids: Vec<String> = self
+            let old_clients: Vec<(String, IpAddr)> = self
                 .clients
                 .iter()
                 .filter(|c| c.info.name == client.name)
-                .map(|c| c.key().clone())
+                .map(|c| (c.key().clone(), c.ip))
                 .collect();
-            for old_id in old_ids {
+            for (old_id, old_ip) in old_clients {
                 info!("清理同名旧客户端: {} ({})", client.name, old_id);
                 self.remove_client(&old_id);
+                self.audit.record(
+                    AuditEvent::new(AuditAction::Evict)
+                        .ip(ip)
+                        .client_id(old_id)
+                        .client_name(&client.name)
+                        .detail(format!("来自 {} 的旧客户端被同名客户端顶替", old_ip)),
+                );
             }
             // 自增数字 ID
             self.next_client_id.fetch_add(1, Ordering::Relaxed).to_string()
@@ -84,9 +313,11 @@ impl ServerState {
         };
         let mut tunnel_infos = Vec::new();
         let mut tunnel_ids = Vec::new();
-
         for config in tunnels {
-            match self.create_tunnel(&client_id, config, tx.clone()).await {
+            match self
+                .create_tunnel(&client_id, config, tx.clone(), port_range, &capabilities)
+                .await
+            {
                 Ok(info) => {
                     tunnel_ids.push(info.id.clone());
                     tunnel_infos.push(info);
@@ -100,12 +331,25 @@ impl ServerState {
         let mut stored_client = client;
         stored_client.id = client_id.clone();
 
+        let mut event = AuditEvent::new(AuditAction::Register)
+            .ip(ip)
+            .client_id(&client_id)
+            .client_name(&stored_client.name)
+            .detail(format!("{} 个隧道", tunnel_infos.len()));
+        event.token_id = grant.token_id.clone();
+        self.audit.record(event);
+
         self.clients.insert(
             client_id.clone(),
             ClientState {
                 info: stored_client,
                 tx,
                 tunnel_ids,
+                grant,
+                kick,
+                ip,
+                protocol_version: protocol_version.min(PROTOCOL_VERSION),
+                capabilities,
             },
         );
 
@@ -117,20 +361,30 @@ impl ServerState {
         &self,
         client_id: &str,
         config: TunnelConfig,
-        client_tx: mpsc::UnboundedSender<WsMessage>,
+        client_tx: SendQueue,
+        (port_start, port_end): (u16, u16),
+        capabilities: &[String],
     ) -> Result<TunnelInfo, String> {
+        if config.e2e && config.compress.is_some() {
+            return Err("e2e 隧道不支持压缩".to_string());
+        }
+        if config.secret {
+            return self.create_secret_tunnel(client_id, config);
+        }
+        let filter = IpFilter::parse(&config.allow, &config.deny)?;
+
         // 分配并绑定端口（find_available_port 直接返回 listener，避免竞态）
         let (listener, server_port) = if let Some(port) = config.remote_port {
-            if port >= self.port_start && port <= self.port_end && !self.is_port_used(port) {
+            if port >= port_start && port <= port_end && !self.is_port_used(port) {
                 let l = TcpListener::bind(format!("0.0.0.0:{}", port))
                     .await
                     .map_err(|e| format!("绑定端口 {} 失败: {}", port, e))?;
                 (l, port)
             } else {
-                self.find_available_port().await?
+                self.find_available_port(port_start, port_end).await?
             }
         } else {
-            self.find_available_port().await?
+            self.find_available_port(port_start, port_end).await?
         };
 
         let now = chrono::Utc::now().to_rfc3339();
@@ -150,91 +404,157 @@ impl ServerState {
             bytes_recv: 0,
             created_at: now.clone(),
             last_active_at: now,
+            allow: config.allow,
+            deny: config.deny,
+            e2e: config.e2e,
+            secret: false,
+            compress: config.compress,
         };
 
         // 启动 accept 循环
         let (shutdown_tx, _) = tokio::sync::broadcast::channel::<()>(1);
         let mut shutdown_rx = shutdown_tx.subscribe();
         let connections = Arc::clone(&self.connections);
+        let next_stream_id = Arc::clone(&self.next_stream_id);
         let tid = tunnel_id.clone();
         let cid = client_id.to_string();
         let sent_counter = Arc::new(AtomicU64::new(0));
         let recv_counter = Arc::new(AtomicU64::new(0));
+        let rejected_counter = Arc::new(AtomicU64::new(0));
         let sent_c = Arc::clone(&sent_counter);
         let recv_c = Arc::clone(&recv_counter);
+        let rejected_c = Arc::clone(&rejected_counter);
+        let closes = Arc::new(CloseStats::default());
+        let closes_c = Arc::clone(&closes);
+        let compression = Arc::new(CompressStats::default());
+        let compression_c = Arc::clone(&compression);
+        // 客户端未协商 deflate 时不压缩
+        let compress = config
+            .compress
+            .filter(|_| capabilities.iter().any(|c| c == capability::DEFLATE));
+        let flow_control = capabilities.iter().any(|c| c == capability::FLOW_CONTROL);
+        let half_close = capabilities.iter().any(|c| c == capability::HALF_CLOSE);
+        let ready_timeout = self.ready_timeout;
 
         tokio::spawn(async move {
             loop {
                 tokio::select! {
                     result = listener.accept() => {
                         match result {
+                            Ok((_, addr)) if !filter.is_allowed(addr.ip()) => {
+                                rejected_c.fetch_add(1, Ordering::Relaxed);
+                                debug!("拒绝连接 {} -> 隧道 {} (不符合 allow/deny 规则)", addr, tid);
+                            }
                             Ok((stream, addr)) => {
                                 debug!("新连接 {} -> 隧道 {}", addr, tid);
-                                let conn_id = Uuid::new_v4().to_string();
-                                let (data_tx, mut data_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+                                let conn_id = alloc_stream_id(&next_stream_id, &connections);
+                                let (data_tx, mut data_rx) = flow::data_channel();
+                                let window = Arc::new(Window::new(flow_control));
+                                let (ready_tx, ready_rx) = oneshot::channel();
 
-                                connections.insert(conn_id.clone(), ConnectionState {
+                                connections.insert(conn_id, ConnectionState {
                                     tunnel_id: tid.clone(),
                                     client_id: cid.clone(),
                                     tx: data_tx,
+                                    window: Arc::clone(&window),
+                                    ready: Some(ready_tx),
+                                    closes: Arc::clone(&closes_c),
+                                    compression: Arc::clone(&compression_c),
                                 });
 
                                 // 通知客户端有新连接
                                 let _ = client_tx.send(WsMessage::NewConnection {
                                     tunnel_id: tid.clone(),
-                                    conn_id: conn_id.clone(),
+                                    conn_id,
+                                    visitor: None,
                                 });
 
                                 let conns = Arc::clone(&connections);
                                 let ctx = client_tx.clone();
-                                let cid2 = conn_id.clone();
+                                let cid2 = conn_id;
                                 let sc = Arc::clone(&sent_c);
                                 let rc = Arc::clone(&recv_c);
+                                let compression = Arc::clone(&compression_c);
 
                                 tokio::spawn(async move {
+                                    // 客户端连上本地服务前不读取外部连接，早到的数据留在内核缓冲区；
+                                    // 客户端连接失败或超时则关闭外部连接
+                                    match tokio::time::timeout(ready_timeout, ready_rx).await {
+                                        Ok(Ok(())) => {}
+                                        // 客户端连接失败或隧道关闭，连接已移除
+                                        Ok(Err(_)) => return,
+                                        Err(_) => {
+                                            warn!("连接 {} 等待客户端连接本地服务超时", cid2);
+                                            let reason = CloseReason::ReadyTimeout;
+                                            if let Some((_, conn)) = conns.remove(&cid2) {
+                                                conn.closes.record(reason);
+                                            }
+                                            let _ = ctx.send(WsMessage::CloseConnection { conn_id: cid2, reason });
+                                            return;
+                                        }
+                                    }
+
                                     let (mut read_half, mut write_half) = stream.into_split();
-                                    let conn_id_r = cid2.clone();
+                                    let conn_id_r = cid2;
                                     let ctx_r = ctx.clone();
                                     let sc_r = Arc::clone(&sc);
+                                    let window_r = Arc::clone(&window);
 
-                                    // 外部 -> 客户端 (recv from external = bytes_recv)
+                                    // 外部 -> 客户端 (recv from external = bytes_recv)，客户端额度用完时暂停读取
                                     let read_task = tokio::spawn(async move {
                                         let mut buf = [0u8; 8192];
                                         loop {
                                             match read_half.read(&mut buf).await {
-                                                Ok(0) => break,
+                                                // 外部关闭写方向，客户端支持半关闭时继续转发另一方向
+                                                Ok(0) if half_close => {
+                                                    return match ctx_r.send(WsMessage::HalfClose { conn_id: conn_id_r }) {
+                                                        Ok(()) => None,
+                                                        Err(_) => Some(CloseReason::Disconnected),
+                                                    };
+                                                }
+                                                Ok(0) => return Some(CloseReason::Closed),
                                                 Ok(n) => {
+                                                    if !window_r.reserve(n).await {
+                                                        return Some(CloseReason::PeerClosed);
+                                                    }
                                                     sc_r.fetch_add(n as u64, Ordering::Relaxed);
-                                                    if ctx_r.send(WsMessage::Data {
-                                                        conn_id: conn_id_r.clone(),
-                                                        data: buf[..n].to_vec(),
-                                                    }).is_err() {
-                                                        break;
+                                                    let data = buf[..n].to_vec();
+                                                    let msg = compress_data(conn_id_r, data, compress, &compression);
+                                                    if !ctx_r.send_data(msg).await {
+                                                        return Some(CloseReason::Disconnected);
                                                     }
                                                 }
-                                                Err(_) => break,
+                                                Err(_) => return Some(CloseReason::Reset),
                                             }
                                         }
                                     });
 
-                                    // 客户端 -> 外部 (sent to external = bytes_sent)
+                                    // 客户端 -> 外部 (sent to external = bytes_sent)，写入后归还额度
                                     let rc_w = Arc::clone(&rc);
+                                    let ctx_w = ctx.clone();
                                     let write_task = tokio::spawn(async move {
                                         while let Some(data) = data_rx.recv().await {
+                                            if data.is_empty() {
+                                                let _ = write_half.shutdown().await;
+                                                return Ok(data_rx);
+                                            }
                                             rc_w.fetch_add(data.len() as u64, Ordering::Relaxed);
                                             if write_half.write_all(&data).await.is_err() {
-                                                break;
+                                                return Err(CloseReason::Reset);
+                                            }
+                                            if let Some(credit) = window.consumed(data.len()) {
+                                                let _ = ctx_w.send(WsMessage::WindowUpdate { conn_id: cid2, credit });
                                             }
                                         }
+                                        Err(CloseReason::PeerClosed)
                                     });
 
-                                    tokio::select! {
-                                        _ = read_task => {}
-                                        _ = write_task => {}
-                                    }
+                                    let reason = half_close::join(read_task, write_task).await;
 
-                                    conns.remove(&cid2);
-                                    let _ = ctx.send(WsMessage::CloseConnection { conn_id: cid2 });
+                                    if let Some((_, conn)) = conns.remove(&cid2) {
+                                        conn.closes.record(reason);
+                                    }
+                                    let _ = ctx.send(WsMessage::CloseConnection { conn_id: cid2, reason });
                                 });
                             }
                             Err(e) => {
@@ -257,6 +577,9 @@ impl ServerState {
                 shutdown: Some(shutdown_tx),
                 bytes_sent: sent_counter,
                 bytes_recv: recv_counter,
+                rejected: rejected_counter,
+                closes,
+                compression,
             },
         );
 
@@ -264,8 +587,483 @@ impl ServerState {
         Ok(info)
     }
 
-    async fn find_available_port(&self) -> Result<(TcpListener, u16), String> {
-        for port in self.port_start..=self.port_end {
+    /// 私密隧道：不绑定端口，只能由持有密钥的访问端经服务端连接
+    fn create_secret_tunnel(
+        &self,
+        client_id: &str,
+        config: TunnelConfig,
+    ) -> Result<TunnelInfo, String> {
+        if !config.allow.is_empty() || !config.deny.is_empty() {
+            return Err("私密隧道不支持 allow/deny".to_string());
+        }
+        let name = config
+            .name
+            .unwrap_or_else(|| format!("secret-{}", config.local_port));
+        // 访问端按 客户端名称/隧道名称 查找，同一客户端内名称不能重复
+        if self
+            .tunnels
+            .iter()
+            .any(|t| t.info.client_id == client_id && t.info.name == name)
+        {
+            return Err(format!("隧道名称 {} 重复", name));
+        }
+
+        let now = chrono::Utc::now().to_rfc3339();
+        let tunnel_id = Uuid::new_v4().to_string();
+        let info = TunnelInfo {
+            id: tunnel_id.clone(),
+            client_id: client_id.to_string(),
+            tunnel_type: config.tunnel_type,
+            name,
+            local_addr: config.local_addr,
+            local_port: config.local_port,
+            server_port: 0,
+            state: "active".to_string(),
+            bytes_sent: 0,
+            bytes_recv: 0,
+            created_at: now.clone(),
+            last_active_at: now,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            e2e: config.e2e,
+            secret: true,
+            compress: config.compress,
+        };
+
+        self.tunnels.insert(
+            tunnel_id.clone(),
+            TunnelState {
+                info: info.clone(),
+                shutdown: None,
+                bytes_sent: Arc::new(AtomicU64::new(0)),
+                bytes_recv: Arc::new(AtomicU64::new(0)),
+                rejected: Arc::new(AtomicU64::new(0)),
+                closes: Arc::new(CloseStats::default()),
+                compression: Arc::new(CompressStats::default()),
+            },
+        );
+
+        info!("私密隧道创建: {} ({})", tunnel_id, info.name);
+        Ok(info)
+    }
+
+    /// 访问端连接其他客户端的隧道：校验后通知隧道客户端建立连接，并在两端的连接之间转发数据
+    ///
+    /// 私密隧道的 MAC 随 NewConnection 转给隧道客户端，由其以密钥校验，服务端不知道密钥；
+    /// 公开隧道要求访问端 Token 的 `client_pattern` 匹配目标客户端名称，
+    /// 设置了 allow/deny 时无法判断访问端来源，不接受访问端连接。
+    /// 隧道客户端连上本地服务 (ConnectionReady) 后才回复访问端 VisitorAccept。
+    /// 失败时返回 (错误码, 原因)，缺少密钥为 AuthFailed。
+    pub fn open_visitor(
+        &self,
+        (visitor_id, ip, visitor_tx): (&str, IpAddr, SendQueue),
+        nonce: &str,
+        request_id: &str,
+        (client_name, tunnel_name): (&str, &str),
+        mac: Option<&str>,
+    ) -> Result<(), (ErrorCode, String)> {
+        let not_found = || {
+            (
+                ErrorCode::Forbidden,
+                format!("隧道 {}/{} 不存在", client_name, tunnel_name),
+            )
+        };
+        let visitor_entitlement = self
+            .clients
+            .get(visitor_id)
+            .map(|c| c.grant.entitlement.clone())
+            .ok_or_else(|| (ErrorCode::Forbidden, "访问端未注册".to_string()))?;
+        let (provider_id, provider_tx, provider_flow, provider_half_close, provider_deflate) = self
+            .clients
+            .iter()
+            .find(|c| c.info.name == client_name)
+            .map(|c| {
+                (
+                    c.key().clone(),
+                    c.tx.clone(),
+                    c.has_capability(capability::FLOW_CONTROL),
+                    c.has_capability(capability::HALF_CLOSE),
+                    c.has_capability(capability::DEFLATE),
+                )
+            })
+            .ok_or_else(not_found)?;
+        let (visitor_flow, visitor_half_close, visitor_deflate) = self
+            .clients
+            .get(visitor_id)
+            .map_or((false, false, false), |c| {
+                (
+                    c.has_capability(capability::FLOW_CONTROL),
+                    c.has_capability(capability::HALF_CLOSE),
+                    c.has_capability(capability::DEFLATE),
+                )
+            });
+        let (tunnel_id, secret, restricted, (sent, recv), stats, compress) = self
+            .tunnels
+            .iter()
+            .find(|t| t.info.client_id == provider_id && t.info.name == tunnel_name)
+            .map(|t| {
+                (
+                    t.key().clone(),
+                    t.info.secret,
+                    !t.info.allow.is_empty() || !t.info.deny.is_empty(),
+                    (Arc::clone(&t.bytes_sent), Arc::clone(&t.bytes_recv)),
+                    (Arc::clone(&t.closes), Arc::clone(&t.compression)),
+                    t.info.compress,
+                )
+            })
+            .ok_or_else(not_found)?;
+        let visitor = match (secret, mac) {
+            (true, Some(mac)) => Some(VisitorAuth {
+                nonce: nonce.to_string(),
+                request_id: request_id.to_string(),
+                mac: mac.to_string(),
+            }),
+            (true, None) => return Err((ErrorCode::AuthFailed, "缺少密钥".to_string())),
+            (false, _) if restricted => {
+                return Err((
+                    ErrorCode::Forbidden,
+                    "隧道设置了 allow/deny 来源限制，不接受访问端连接".to_string(),
+                ))
+            }
+            (false, _) => {
+                visitor_entitlement
+                    .check_client_name(client_name)
+                    .map_err(|_| {
+                        (
+                            ErrorCode::Forbidden,
+                            format!("访问端的 Token 未授权访问客户端 {}", client_name),
+                        )
+                    })?;
+                None
+            }
+        };
+
+        let conn_id = self.alloc_stream_id();
+        let provider_conn = self.alloc_stream_id();
+        let visitor_window = Arc::new(Window::new(visitor_flow));
+        let provider_window = Arc::new(Window::new(provider_flow));
+        // 两端分别按各自协商的能力决定是否压缩
+        let provider_compress = compress.filter(|_| provider_deflate);
+        let visitor_compress = compress.filter(|_| visitor_deflate);
+        // 访问端 -> 隧道客户端 (bytes_sent)，隧道客户端 -> 访问端 (bytes_recv)
+        self.splice_half(
+            (&tunnel_id, &stats),
+            (conn_id, visitor_id, &visitor_tx, &visitor_window),
+            (
+                provider_conn,
+                &provider_tx,
+                &provider_window,
+                (provider_half_close, provider_compress),
+            ),
+            sent,
+        );
+        self.splice_half(
+            (&tunnel_id, &stats),
+            (provider_conn, &provider_id, &provider_tx, &provider_window),
+            (
+                conn_id,
+                &visitor_tx,
+                &visitor_window,
+                (visitor_half_close, visitor_compress),
+            ),
+            recv,
+        );
+        self.pending_visitors.insert(
+            provider_conn,
+            PendingVisitor {
+                tx: visitor_tx,
+                client_id: visitor_id.to_string(),
+                ip,
+                request_id: request_id.to_string(),
+                conn_id,
+                compress: visitor_compress,
+            },
+        );
+        let pending = Arc::clone(&self.pending_visitors);
+        let connections = Arc::clone(&self.connections);
+        let ready_timeout = self.ready_timeout;
+        let ptx = provider_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ready_timeout).await;
+            let message = "等待隧道客户端连接本地服务超时".to_string();
+            if fail_visitor(&pending, provider_conn, message) {
+                warn!("连接 {} 等待客户端连接本地服务超时", provider_conn);
+                let reason = CloseReason::ReadyTimeout;
+                if let Some((_, conn)) = connections.remove(&provider_conn) {
+                    conn.closes.record(reason);
+                }
+                let _ = ptx.send(WsMessage::CloseConnection {
+                    conn_id: provider_conn,
+                    reason,
+                });
+            }
+        });
+
+        debug!(
+            "访问端连接 {} -> 隧道 {} ({})",
+            conn_id, tunnel_id, provider_conn
+        );
+        let _ = provider_tx.send(WsMessage::NewConnection {
+            tunnel_id,
+            conn_id: provider_conn,
+            visitor,
+        });
+        Ok(())
+    }
+
+    /// 把 `conn_id` 上收到的数据转发到另一端的 `peer_conn`，任一端关闭时关闭另一端
+    ///
+    /// 逐跳流量控制：等到对端有额度才转发，转发后才向本端归还额度。
+    /// 本端 HalfClose 时转发给支持半关闭的对端，否则关闭整个连接。
+    /// 本端的数据收到时已解压，按对端的压缩阈值 `peer_compress` 重新压缩。
+    fn splice_half(
+        &self,
+        (tunnel_id, (closes, compression)): (&str, &(Arc<CloseStats>, Arc<CompressStats>)),
+        (conn_id, client_id, tx, window): (StreamId, &str, &Tx, &Arc<Window>),
+        (peer_conn, peer_tx, peer_window, (peer_half_close, peer_compress)): (
+            StreamId,
+            &Tx,
+            &Arc<Window>,
+            (bool, Option<u32>),
+        ),
+        counter: Arc<AtomicU64>,
+    ) {
+        let (data_tx, mut data_rx) = flow::data_channel();
+        self.connections.insert(
+            conn_id,
+            ConnectionState {
+                tunnel_id: tunnel_id.to_string(),
+                client_id: client_id.to_string(),
+                tx: data_tx,
+                window: Arc::clone(window),
+                ready: None,
+                closes: Arc::clone(closes),
+                compression: Arc::clone(compression),
+            },
+        );
+
+        let connections = Arc::clone(&self.connections);
+        let pending = Arc::clone(&self.pending_visitors);
+        let (tx, window) = (tx.clone(), Arc::clone(window));
+        let (peer_tx, peer_window) = (peer_tx.clone(), Arc::clone(peer_window));
+        let compression = Arc::clone(compression);
+        tokio::spawn(async move {
+            let reason = loop {
+                // 本端连接已移除，关闭原因已在本端计数
+                let Some(data) = data_rx.recv().await else {
+                    break CloseReason::PeerClosed;
+                };
+                if data.is_empty() {
+                    // 对端不支持半关闭，关闭整个连接
+                    if !peer_half_close {
+                        break CloseReason::Closed;
+                    }
+                    if peer_tx
+                        .send(WsMessage::HalfClose { conn_id: peer_conn })
+                        .is_err()
+                    {
+                        break CloseReason::Disconnected;
+                    }
+                    // 本端不会再发来数据，等待本端关闭连接
+                    while data_rx.recv().await.is_some() {}
+                    break CloseReason::PeerClosed;
+                }
+                let len = data.len();
+                counter.fetch_add(len as u64, Ordering::Relaxed);
+                if !peer_window.reserve(len).await {
+                    break CloseReason::PeerClosed;
+                }
+                let msg = compress_data(peer_conn, data, peer_compress, &compression);
+                if !peer_tx.send_data(msg).await {
+                    break CloseReason::Disconnected;
+                }
+                if let Some(credit) = window.consumed(len) {
+                    let _ = tx.send(WsMessage::WindowUpdate { conn_id, credit });
+                }
+            };
+            // 隧道侧在就绪前关闭 (旧版客户端连接本地服务失败)，访问端还不知道流 ID，直接回复失败
+            fail_visitor(&pending, conn_id, "隧道客户端连接本地服务失败".to_string());
+            pending.remove(&peer_conn);
+            if let Some((_, conn)) = connections.remove(&peer_conn) {
+                conn.closes.record(reason);
+            }
+            let _ = peer_tx.send(WsMessage::CloseConnection {
+                conn_id: peer_conn,
+                reason,
+            });
+        });
+    }
+
+    /// 把客户端发来的数据交给对应连接，只接受该连接所属客户端发来的数据
+    ///
+    /// 访问端与隧道客户端的连接都在同一张表中，避免一个客户端向另一个客户端的连接注入数据。
+    /// 压缩的数据解压后再交给连接，无法解压时关闭连接。
+    pub fn forward_data(
+        &self,
+        conn_id: StreamId,
+        client_id: Option<&str>,
+        data: Vec<u8>,
+        compressed: bool,
+    ) {
+        // 空数据在连接通道中表示 HalfClose
+        if data.is_empty() {
+            return;
+        }
+        let Some(conn) = self.connections.get(&conn_id) else {
+            return;
+        };
+        if Some(conn.client_id.as_str()) != client_id {
+            return;
+        }
+        let wire = data.len();
+        let data = if compressed {
+            match compress::decompress(&data) {
+                Some(data) => data,
+                None => {
+                    warn!("连接 {} 的压缩数据无法解压，关闭连接", conn_id);
+                    drop(conn);
+                    self.close_connection(conn_id, CloseReason::InvalidData);
+                    return;
+                }
+            }
+        } else {
+            data
+        };
+        conn.compression.record(data.len(), wire);
+        if conn.window.deliver(&conn.tx, data) {
+            return;
+        }
+        // 客户端未遵守流量控制，关闭连接而不是无限缓存
+        warn!("连接 {} 的数据超出窗口或缓存上限，关闭连接", conn_id);
+        drop(conn);
+        self.close_connection(conn_id, CloseReason::FlowControl);
+    }
+
+    /// 客户端关闭连接，只接受该连接所属客户端的 CloseConnection
+    pub fn connection_closed(
+        &self,
+        conn_id: StreamId,
+        client_id: Option<&str>,
+        reason: CloseReason,
+    ) {
+        let removed = self
+            .connections
+            .remove_if(&conn_id, |_, c| Some(c.client_id.as_str()) == client_id);
+        if let Some((_, conn)) = removed {
+            conn.closes.record(reason);
+        }
+    }
+
+    /// 服务端主动关闭连接，计数并通知连接所属客户端
+    fn close_connection(&self, conn_id: StreamId, reason: CloseReason) {
+        let Some((_, conn)) = self.connections.remove(&conn_id) else {
+            return;
+        };
+        conn.closes.record(reason);
+        if let Some(client) = self.clients.get(&conn.client_id) {
+            let _ = client
+                .tx
+                .send(WsMessage::CloseConnection { conn_id, reason });
+        }
+    }
+
+    /// 客户端的本地连接关闭了写方向，只接受该连接所属客户端的 HalfClose
+    pub fn half_close(&self, conn_id: StreamId, client_id: Option<&str>) {
+        if let Some(conn) = self.connections.get(&conn_id) {
+            if Some(conn.client_id.as_str()) == client_id {
+                // 通道为 HalfClose 预留了容量，重复的 HalfClose 直接丢弃
+                let _ = conn.tx.try_send(Vec::new());
+            }
+        }
+    }
+
+    /// 客户端归还连接的发送额度，只接受该连接所属客户端的 WindowUpdate
+    pub fn grant_window(&self, conn_id: StreamId, client_id: Option<&str>, credit: u32) {
+        if let Some(conn) = self.connections.get(&conn_id) {
+            if Some(conn.client_id.as_str()) == client_id {
+                conn.window.grant(credit);
+            }
+        }
+    }
+
+    /// 隧道客户端连接就绪，开始转发外部连接的数据，若为访问端连接则通知访问端
+    pub fn connection_ready(&self, conn_id: StreamId, client_id: Option<&str>) {
+        let Some(mut conn) = self.connections.get_mut(&conn_id) else {
+            return;
+        };
+        if Some(conn.client_id.as_str()) != client_id {
+            return;
+        }
+        if let Some(ready) = conn.ready.take() {
+            let _ = ready.send(());
+        }
+        drop(conn);
+        if let Some((_, visitor)) = self.pending_visitors.remove(&conn_id) {
+            let _ = visitor.tx.send(WsMessage::VisitorAccept {
+                request_id: visitor.request_id,
+                conn_id: visitor.conn_id,
+                success: true,
+                message: None,
+                compress: visitor.compress,
+            });
+        }
+    }
+
+    /// 隧道客户端连接本地服务失败，关闭外部连接，访问端连接则把原因回复给访问端
+    pub fn connection_failed(
+        &self,
+        conn_id: StreamId,
+        client_id: Option<&str>,
+        reason: CloseReason,
+        message: &str,
+    ) {
+        match self.connections.get(&conn_id) {
+            Some(conn) if Some(conn.client_id.as_str()) == client_id => {}
+            _ => return,
+        }
+        warn!("连接 {} 连接本地服务失败: {}", conn_id, message);
+        if reason == CloseReason::AuthFailed {
+            if let Some(visitor) = self.pending_visitors.get(&conn_id) {
+                let detail = format!("访问端连接 {}: {}", conn_id, message);
+                self.visitor_auth_failed(Some(visitor.client_id.clone()), visitor.ip, detail);
+            }
+        }
+        let message = match reason {
+            CloseReason::AuthFailed => message.to_string(),
+            _ => format!("隧道客户端连接本地服务失败: {}", message),
+        };
+        fail_visitor(&self.pending_visitors, conn_id, message);
+        if let Some((_, conn)) = self.connections.remove(&conn_id) {
+            conn.closes.record(reason);
+        }
+    }
+
+    /// 访问端的私密隧道密钥错误，记入审计日志并计入来源 IP 的认证失败次数，防止暴力猜测密钥；
+    /// 达到上限被封禁时返回 true
+    pub fn visitor_auth_failed(
+        &self,
+        client_id: Option<String>,
+        ip: IpAddr,
+        detail: String,
+    ) -> bool {
+        let mut event = AuditEvent::new(AuditAction::AuthFailure)
+            .ip(ip)
+            .detail(detail);
+        event.client_id = client_id;
+        self.audit.record(event);
+        self.limiter.record_failure(ip)
+    }
+
+    fn alloc_stream_id(&self) -> StreamId {
+        alloc_stream_id(&self.next_stream_id, &self.connections)
+    }
+
+    async fn find_available_port(
+        &self,
+        port_start: u16,
+        port_end: u16,
+    ) -> Result<(TcpListener, u16), String> {
+        for port in port_start..=port_end {
             if !self.is_port_used(port) {
                 if let Ok(listener) = TcpListener::bind(format!("0.0.0.0:{}", port)).await {
                     return Ok((listener, port));
@@ -301,14 +1099,14 @@ impl ServerState {
         }
 
         // 清理该隧道的所有连接
-        let conn_ids: Vec<String> = self
+        let conn_ids: Vec<StreamId> = self
             .connections
             .iter()
             .filter(|c| c.tunnel_id == tunnel_id)
-            .map(|c| c.key().clone())
+            .map(|c| *c.key())
             .collect();
         for conn_id in conn_ids {
-            self.connections.remove(&conn_id);
+            self.close_connection(conn_id, CloseReason::TunnelClosed);
         }
 
         info!("隧道关闭: {}", tunnel_id);
@@ -320,15 +1118,29 @@ impl ServerState {
         &self,
         client_id: &str,
         config: TunnelConfig,
-        client_tx: mpsc::UnboundedSender<WsMessage>,
+        client_tx: SendQueue,
     ) -> Result<TunnelInfo, String> {
-        // 确认客户端存在
-        if !self.clients.contains_key(client_id) {
-            return Err("客户端不存在".to_string());
-        }
+        // 确认客户端存在，并校验 Token 授权范围
+        let (port_range, capabilities) = match self.clients.get(client_id) {
+            Some(client) => {
+                if config.e2e && !client.has_capability(capability::E2E) {
+                    return Err("客户端不支持端到端加密，请升级 cec-tunnel".to_string());
+                }
+                self.check_tunnel_cap(client.tunnel_ids.len())?;
+                let entitlement = &client.grant.entitlement;
+                entitlement.check_tunnel(&config, client.tunnel_ids.len())?;
+                (
+                    entitlement.port_range(self.port_start, self.port_end),
+                    client.capabilities.clone(),
+                )
+            }
+            None => return Err("客户端不存在".to_string()),
+        };
 
         // 创建隧道
-        let info = self.create_tunnel(client_id, config, client_tx).await?;
+        let info = self
+            .create_tunnel(client_id, config, client_tx, port_range, &capabilities)
+            .await?;
 
         // 把 tunnel_id 加到客户端的 tunnel_ids
         if let Some(mut client) = self.clients.get_mut(client_id) {
@@ -338,8 +1150,60 @@ impl ServerState {
         Ok(info)
     }
 
+    /// 校验客户端版本不低于 --min-client-version
+    pub fn check_client_version(&self, client: &ClientInfo) -> Result<(), (ErrorCode, String)> {
+        let Some(min) = &self.min_client_version else {
+            return Ok(());
+        };
+        let too_old = match (parse_version(&client.version), parse_version(min)) {
+            (Some(version), Some(min)) => version < min,
+            // 无法识别的版本号按过旧处理
+            _ => true,
+        };
+        if too_old {
+            let version = match client.version.as_str() {
+                "" => "未知",
+                version => version,
+            };
+            return Err((
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "客户端版本 {} 低于服务端要求的最低版本 {}，请升级 cec-tunnel",
+                    version, min
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 服务端全局的单客户端隧道数上限
+    fn check_tunnel_cap(&self, existing: usize) -> Result<(), String> {
+        if self.max_tunnels_per_client > 0 && existing >= self.max_tunnels_per_client {
+            return Err(format!(
+                "隧道数量超出服务端限制 ({})",
+                self.max_tunnels_per_client
+            ));
+        }
+        Ok(())
+    }
+
+    /// 断开所有使用指定 Token 注册的客户端，返回断开数量
+    pub fn remove_clients_by_token(&self, token_id: &str) -> usize {
+        let client_ids: Vec<String> = self
+            .clients
+            .iter()
+            .filter(|c| c.grant.token_id.as_deref() == Some(token_id))
+            .map(|c| c.key().clone())
+            .collect();
+        for client_id in &client_ids {
+            self.remove_client(client_id);
+        }
+        client_ids.len()
+    }
+
     pub fn remove_client(&self, client_id: &str) {
         if let Some((_, client)) = self.clients.remove(client_id) {
+            client.kick.notify_one();
             for tunnel_id in client.tunnel_ids {
                 if let Some((_, tunnel)) = self.tunnels.remove(&tunnel_id) {
                     if let Some(shutdown) = tunnel.shutdown {
@@ -349,16 +1213,297 @@ impl ServerState {
                 }
             }
             // 清理该客户端的所有连接
-            let conn_ids: Vec<String> = self
+            let conn_ids: Vec<StreamId> = self
                 .connections
                 .iter()
                 .filter(|c| c.client_id == client_id)
-                .map(|c| c.key().clone())
+                .map(|c| *c.key())
                 .collect();
             for conn_id in conn_ids {
-                self.connections.remove(&conn_id);
+                if let Some((_, conn)) = self.connections.remove(&conn_id) {
+                    conn.closes.record(CloseReason::Disconnected);
+                }
             }
             info!("客户端断开: {}", client_id);
         }
     }
 }
+
+/// 回复等待中的访问端连接失败，访问端还不知道流 ID；返回是否有等待中的访问端
+fn fail_visitor(
+    pending: &DashMap<StreamId, PendingVisitor>,
+    conn_id: StreamId,
+    message: String,
+) -> bool {
+    let Some((_, visitor)) = pending.remove(&conn_id) else {
+        return false;
+    };
+    let _ = visitor.tx.send(WsMessage::VisitorAccept {
+        request_id: visitor.request_id,
+        conn_id: 0,
+        success: false,
+        message: Some(message),
+        compress: None,
+    });
+    true
+}
+
+/// 分配未被占用的流 ID，计数器回绕后跳过 0 和仍在使用的 ID
+fn alloc_stream_id(next: &AtomicU32, connections: &DashMap<StreamId, ConnectionState>) -> StreamId {
+    loop {
+        let id = next.fetch_add(1, Ordering::Relaxed);
+        if id != 0 && !connections.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+/// 解析 `主版本.次版本[.修订号]` 形式的版本号，忽略前缀 v 和预发布后缀 (如 -beta)
+pub fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let version = version.split(['-', '+']).next()?;
+    let mut parts = version.split('.').map(|p| p.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next()??;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditQuery;
+    use crate::common::auth;
+    use crate::common::protocol::TunnelType;
+    use crate::common::queue::{self, QueueReceiver};
+
+    fn client(name: &str) -> ClientInfo {
+        ClientInfo {
+            id: String::new(),
+            name: name.to_string(),
+            version: String::new(),
+            os: String::new(),
+            arch: String::new(),
+            hostname: String::new(),
+            local_ip: String::new(),
+        }
+    }
+
+    async fn register(
+        state: &ServerState,
+        name: &str,
+        tunnels: Vec<TunnelConfig>,
+    ) -> (String, QueueReceiver) {
+        let (tx, rx) = queue::channel();
+        let session = ClientSession {
+            tx,
+            kick: Arc::new(Notify::new()),
+            ip: IpAddr::from([127, 0, 0, 1]),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capability::SUPPORTED
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+        };
+        let (id, _) = state
+            .register_client(client(name), tunnels, Grant::default(), session)
+            .await
+            .unwrap();
+        (id, rx)
+    }
+
+    async fn recv(rx: &mut QueueReceiver) -> Option<WsMessage> {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("等待消息超时")
+    }
+
+    #[test]
+    fn parse_version_formats() {
+        assert_eq!(parse_version("0.3.1"), Some((0, 3, 1)));
+        assert_eq!(parse_version("v0.3.1"), Some((0, 3, 1)));
+        assert_eq!(parse_version("0.3"), Some((0, 3, 0)));
+        assert_eq!(parse_version("v1.2"), Some((1, 2, 0)));
+        // 预发布和构建后缀被忽略
+        assert_eq!(parse_version("0.3.0-beta"), Some((0, 3, 0)));
+        assert_eq!(parse_version("0.3.0-beta.2"), Some((0, 3, 0)));
+        assert_eq!(parse_version("0.3-rc1"), Some((0, 3, 0)));
+        assert_eq!(parse_version("0.3.0+build.5"), Some((0, 3, 0)));
+
+        for bad in [
+            "", "v", "1", "a.b.c", "0.3.x", "0.3.1.4", "0..1", " 0.3.1", "V0.3.1",
+        ] {
+            assert_eq!(parse_version(bad), None, "{:?}", bad);
+        }
+    }
+
+    fn check_version(min: Option<&str>, version: &str) -> Result<(), (ErrorCode, String)> {
+        let mut state = ServerState::new(20000, 20010, TokenRegistry::default(), None);
+        state.min_client_version = min.map(str::to_string);
+        let client = ClientInfo {
+            version: version.to_string(),
+            ..client("office")
+        };
+        state.check_client_version(&client)
+    }
+
+    #[test]
+    fn client_version_gate() {
+        // 未配置时不检查
+        assert!(check_version(None, "").is_ok());
+
+        let min = Some("0.3.0");
+        for ok in ["0.3.0", "v0.3.0", "0.3", "0.3.0-beta"] {
+            assert!(check_version(min, ok).is_ok(), "{:?}", ok);
+        }
+        for ok in ["0.3.1", "0.10.0", "1.0"] {
+            assert!(check_version(min, ok).is_ok(), "{:?}", ok);
+        }
+        for old in ["0.2.9", "v0.2", "0.2.99-rc1"] {
+            let (code, _) = check_version(min, old).unwrap_err();
+            assert_eq!(code, ErrorCode::UnsupportedVersion, "{:?}", old);
+        }
+        // 缺少或无法识别的版本号按过旧处理
+        for unknown in ["", "dev", "latest", "0.3.0.1"] {
+            let (code, _) = check_version(min, unknown).unwrap_err();
+            assert_eq!(code, ErrorCode::UnsupportedVersion, "{:?}", unknown);
+        }
+        let (_, message) = check_version(min, "").unwrap_err();
+        assert!(message.contains("未知"));
+
+        assert!(check_version(Some("v0.3"), "0.3.0").is_ok());
+        assert!(check_version(Some("v0.3"), "0.2.9").is_err());
+    }
+
+    /// 注册提供私密隧道 office/ssh 的隧道客户端和访问端 laptop，返回 (访问端 ID, 隧道客户端, 访问端)
+    async fn secret_tunnel(state: &ServerState) -> (String, QueueReceiver, QueueReceiver) {
+        let ssh = TunnelConfig {
+            tunnel_type: TunnelType::Tcp,
+            local_addr: "127.0.0.1".to_string(),
+            local_port: 22,
+            remote_port: None,
+            name: Some("ssh".to_string()),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            e2e: false,
+            secret: true,
+            secret_key: None,
+            compress: None,
+        };
+        let (_, provider_rx) = register(state, "office", vec![ssh]).await;
+        let (visitor_id, visitor_rx) = register(state, "laptop", Vec::new()).await;
+        (visitor_id, provider_rx, visitor_rx)
+    }
+
+    fn open_secret(
+        state: &ServerState,
+        visitor_id: &str,
+        mac: Option<&str>,
+    ) -> Result<(), ErrorCode> {
+        let visitor_tx = state.clients.get(visitor_id).unwrap().tx.clone();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        state
+            .open_visitor(
+                (visitor_id, ip, visitor_tx),
+                "nonce",
+                "req-1",
+                ("office", "ssh"),
+                mac,
+            )
+            .map_err(|(code, _)| code)
+    }
+
+    #[tokio::test]
+    async fn secret_tunnel_mac_relayed_to_provider() {
+        let state = ServerState::new(20000, 20010, TokenRegistry::default(), None);
+        let (visitor_id, mut provider_rx, mut visitor_rx) = secret_tunnel(&state).await;
+
+        // 服务端不知道密钥，缺少 MAC 时直接拒绝，否则原样转给隧道客户端
+        assert_eq!(
+            open_secret(&state, &visitor_id, None),
+            Err(ErrorCode::AuthFailed)
+        );
+        let mac = auth::sign("wrong", "nonce", "req-1");
+        open_secret(&state, &visitor_id, Some(&mac)).unwrap();
+        let provider_conn = match recv(&mut provider_rx).await {
+            Some(WsMessage::NewConnection {
+                conn_id,
+                visitor: Some(visitor),
+                ..
+            }) => {
+                assert_eq!(visitor.nonce, "nonce");
+                assert_eq!(visitor.request_id, "req-1");
+                assert!(!auth::verify(
+                    "s3cret",
+                    &visitor.nonce,
+                    &visitor.request_id,
+                    &visitor.mac
+                ));
+                conn_id
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+
+        // 隧道客户端校验失败，访问端收到失败并计入认证失败
+        let office = state
+            .clients
+            .iter()
+            .find(|c| c.info.name == "office")
+            .map(|c| c.key().clone());
+        state.connection_failed(
+            provider_conn,
+            office.as_deref(),
+            CloseReason::AuthFailed,
+            "密钥错误",
+        );
+        match recv(&mut visitor_rx).await {
+            Some(WsMessage::VisitorAccept { success, .. }) => assert!(!success),
+            other => panic!("unexpected {:?}", other),
+        }
+        let query = AuditQuery {
+            action: Some(AuditAction::AuthFailure),
+            client: Some(visitor_id.clone()),
+            ..Default::default()
+        };
+        let (_, total) = state.audit.query(&query).await.unwrap();
+        assert_eq!(total, 1);
+    }
+
+    #[tokio::test]
+    async fn visitor_ready_timeout_closes_provider_stream() {
+        let mut state = ServerState::new(20000, 20010, TokenRegistry::default(), None);
+        state.ready_timeout = Duration::from_millis(50);
+        let (visitor_id, mut provider_rx, mut visitor_rx) = secret_tunnel(&state).await;
+
+        let mac = auth::sign("s3cret", "nonce", "req-1");
+        open_secret(&state, &visitor_id, Some(&mac)).unwrap();
+
+        // 隧道客户端收到 NewConnection 后始终不回 ConnectionReady
+        let provider_conn = match recv(&mut provider_rx).await {
+            Some(WsMessage::NewConnection { conn_id, .. }) => conn_id,
+            other => panic!("unexpected {:?}", other),
+        };
+        match recv(&mut provider_rx).await {
+            Some(WsMessage::CloseConnection { conn_id, reason }) => {
+                assert_eq!(conn_id, provider_conn);
+                assert_eq!(reason, CloseReason::ReadyTimeout);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match recv(&mut visitor_rx).await {
+            Some(WsMessage::VisitorAccept {
+                request_id,
+                success,
+                ..
+            }) => {
+                assert_eq!(request_id, "req-1");
+                assert!(!success);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(!state.connections.contains_key(&provider_conn));
+    }
+}
@@ -1,2 +1,9 @@
+pub mod acme;
+pub mod admin;
+pub mod audit;
 pub mod handler;
+pub mod limiter;
 pub mod manager;
+pub mod signed_token;
+pub mod tls;
+pub mod token;
@@ -0,0 +1,257 @@
+//! 自包含签名 Token
+//!
+//! 格式: `cec1.<base64url(claims JSON)>.<base64url(HMAC-SHA256 签名)>`
+//!
+//! 声明中携带客户端名称、过期时间和授权范围，服务端仅凭签名密钥即可离线校验，
+//! 无需 Token 数据库，适合批量下发给现场设备。握手时客户端只发送不含签名的部分，
+//! 服务端用签名密钥还原完整 Token 后再校验挑战应答，签名本身不在网络上传输。
+
+use crate::common::auth::{self, SIGNED_TOKEN_PREFIX};
+use crate::common::protocol::ErrorCode;
+use crate::token::{Entitlement, Grant};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+/// Token 声明，客户端名称放在 `client_pattern` 中（支持 `*` 通配）
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// 签发时间 (Unix 秒)
+    pub iat: i64,
+    /// 过期时间 (Unix 秒)
+    pub exp: i64,
+    #[serde(flatten)]
+    pub entitlement: Entitlement,
+}
+
+pub struct SigningKey {
+    key: hmac::Key,
+}
+
+impl SigningKey {
+    /// 从文件读取签名密钥，首尾空白会被忽略
+    pub fn load(path: &str) -> Result<Self> {
+        let secret =
+            std::fs::read_to_string(path).with_context(|| format!("读取签名密钥 {} 失败", path))?;
+        let secret = secret.trim();
+        if secret.len() < 16 {
+            anyhow::bail!("签名密钥 {} 过短，至少需要 16 个字符", path);
+        }
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        })
+    }
+
+    /// 签发 Token
+    pub fn mint(&self, claims: &Claims) -> Result<String> {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+        Ok(self.complete(&format!("{}.{}", SIGNED_TOKEN_PREFIX, payload)))
+    }
+
+    /// 给不含签名的部分补上签名，得到完整 Token
+    fn complete(&self, unsigned: &str) -> String {
+        let sig = hmac::sign(&self.key, unsigned.as_bytes());
+        format!("{}.{}", unsigned, URL_SAFE_NO_PAD.encode(sig.as_ref()))
+    }
+
+    /// 校验签名 Token 的挑战应答，并检查过期时间
+    pub fn authorize(
+        &self,
+        unsigned: &str,
+        nonce: &str,
+        client_name: &str,
+        mac: Option<&str>,
+    ) -> Result<Grant, (ErrorCode, String)> {
+        let auth_failed = |msg: &str| (ErrorCode::AuthFailed, msg.to_string());
+
+        let mac = mac.ok_or_else(|| auth_failed("认证失败: 缺少 Token"))?;
+        let token = self.complete(unsigned);
+        if !auth::verify(&token, nonce, client_name, mac) {
+            return Err(auth_failed("认证失败: Token 无效"));
+        }
+
+        let payload = unsigned
+            .strip_prefix(SIGNED_TOKEN_PREFIX)
+            .and_then(|p| p.strip_prefix('.'))
+            .ok_or_else(|| auth_failed("认证失败: Token 格式错误"))?;
+        let claims: Claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .ok_or_else(|| auth_failed("认证失败: Token 声明无法解析"))?;
+
+        if claims.exp <= chrono::Utc::now().timestamp() {
+            let at = chrono::DateTime::from_timestamp(claims.exp, 0)
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default();
+            return Err((
+                ErrorCode::TokenExpired,
+                format!("认证失败: Token 已于 {} 过期", at),
+            ));
+        }
+
+        Ok(Grant {
+            token_id: None,
+            entitlement: claims.entitlement,
+            cert_cn: None,
+        })
+    }
+}
+
+/// 解析时长，支持 `30d` / `12h` / `90m` / `3600s` / 纯数字秒
+pub fn parse_duration(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (num, unit) = match s.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    let n: i64 = num.parse().ok()?;
+    let secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return None,
+    };
+    n.checked_mul(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: &str = "nonce";
+    const NAME: &str = "office-1";
+
+    fn key(secret: &str) -> SigningKey {
+        SigningKey {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    fn claims(exp_in: i64) -> Claims {
+        let now = chrono::Utc::now().timestamp();
+        Claims {
+            iat: now,
+            exp: now + exp_in,
+            entitlement: Entitlement {
+                client_pattern: Some("office-*".to_string()),
+                max_tunnels: Some(2),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// 模拟客户端握手：发送不含签名的部分，并以完整 Token 计算挑战应答
+    fn handshake(token: &str) -> (&str, String) {
+        let unsigned = auth::unsigned_part(token).unwrap();
+        (unsigned, auth::sign(token, NONCE, NAME))
+    }
+
+    #[test]
+    fn mint_then_authorize() {
+        let key = key("0123456789abcdef");
+        let token = key.mint(&claims(3600)).unwrap();
+        assert!(token.starts_with("cec1."));
+        let (unsigned, mac) = handshake(&token);
+        let grant = key.authorize(unsigned, NONCE, NAME, Some(&mac)).unwrap();
+        assert_eq!(
+            grant.entitlement.client_pattern.as_deref(),
+            Some("office-*")
+        );
+        assert_eq!(grant.entitlement.max_tunnels, Some(2));
+        assert!(grant.token_id.is_none());
+
+        // 应答与 nonce、客户端名称绑定
+        let err = key
+            .authorize(unsigned, "other", NAME, Some(&mac))
+            .unwrap_err();
+        assert_eq!(err.0, ErrorCode::AuthFailed);
+        let err = key
+            .authorize(unsigned, NONCE, "office-2", Some(&mac))
+            .unwrap_err();
+        assert_eq!(err.0, ErrorCode::AuthFailed);
+        let err = key.authorize(unsigned, NONCE, NAME, None).unwrap_err();
+        assert_eq!(err.0, ErrorCode::AuthFailed);
+    }
+
+    #[test]
+    fn tampered_claims_rejected() {
+        let key = key("0123456789abcdef");
+        let token = key.mint(&claims(3600)).unwrap();
+        let (_, sig) = token.rsplit_once('.').unwrap();
+
+        // 放宽授权范围后沿用原签名
+        let mut wider = claims(3600);
+        wider.entitlement.client_pattern = Some("*".to_string());
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&wider).unwrap());
+        let forged = format!("{}.{}.{}", SIGNED_TOKEN_PREFIX, payload, sig);
+        let (unsigned, mac) = handshake(&forged);
+        let err = key
+            .authorize(unsigned, NONCE, NAME, Some(&mac))
+            .unwrap_err();
+        assert_eq!(err.0, ErrorCode::AuthFailed);
+    }
+
+    #[test]
+    fn tampered_signature_rejected() {
+        let key = key("0123456789abcdef");
+        let token = key.mint(&claims(3600)).unwrap();
+        let (unsigned, _) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", unsigned, URL_SAFE_NO_PAD.encode([0u8; 32]));
+        let (unsigned, mac) = handshake(&forged);
+        let err = key
+            .authorize(unsigned, NONCE, NAME, Some(&mac))
+            .unwrap_err();
+        assert_eq!(err.0, ErrorCode::AuthFailed);
+    }
+
+    #[test]
+    fn expired_token() {
+        let key = key("0123456789abcdef");
+        let token = key.mint(&claims(-1)).unwrap();
+        let (unsigned, mac) = handshake(&token);
+        let err = key
+            .authorize(unsigned, NONCE, NAME, Some(&mac))
+            .unwrap_err();
+        assert_eq!(err.0, ErrorCode::TokenExpired);
+        assert_eq!(i32::from(err.0), 498);
+        assert!(err.1.contains("过期"));
+    }
+
+    #[test]
+    fn wrong_signing_key() {
+        let token = key("0123456789abcdef").mint(&claims(3600)).unwrap();
+        let (unsigned, mac) = handshake(&token);
+        let err = key("fedcba9876543210")
+            .authorize(unsigned, NONCE, NAME, Some(&mac))
+            .unwrap_err();
+        assert_eq!(err.0, ErrorCode::AuthFailed);
+    }
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(parse_duration("3600"), Some(3600));
+        assert_eq!(parse_duration("3600s"), Some(3600));
+        assert_eq!(parse_duration("90m"), Some(5400));
+        assert_eq!(parse_duration("12h"), Some(43200));
+        assert_eq!(parse_duration(" 30d "), Some(30 * 86400));
+    }
+
+    #[test]
+    fn duration_edges() {
+        // 0 可以解析，由 mint-token 拒绝非正数
+        assert_eq!(parse_duration("0"), Some(0));
+        assert_eq!(parse_duration("0d"), Some(0));
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("5D"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("   "), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("1.5h"), None);
+        assert_eq!(parse_duration("9223372036854775807"), Some(i64::MAX));
+        assert_eq!(parse_duration("9223372036854775807d"), None);
+        assert_eq!(parse_duration("99999999999999999999"), None);
+    }
+}
@@ -0,0 +1,235 @@
+//! wss:// TLS 配置、证书热加载与客户端证书认证 (mTLS)
+
+use crate::acme::{AcmeResolver, ACME_TLS_ALPN};
+use crate::common::tls::{load_certs, load_key};
+use anyhow::{Context, Result};
+use axum_server::accept::{Accept, DefaultAcceptor};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use rustls::server::{WantsServerCert, WebPkiClientVerifier};
+use rustls::{ConfigBuilder, RootCertStore, ServerConfig};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tower_http::add_extension::AddExtension;
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// wss:// 连接的客户端证书信息，作为请求扩展传给处理器
+#[derive(Debug, Clone, Default)]
+pub struct PeerCert {
+    /// 客户端证书 Subject CN，未提供证书时为空
+    pub common_name: Option<String>,
+}
+
+/// 构建服务端 TLS 配置；指定 `client_ca` 时校验客户端证书
+///
+/// 客户端证书在 TLS 层是可选的，管理 API 仍可不带证书访问；
+/// 是否必须携带证书由 `/tunnel` 升级时检查。
+pub fn server_config(cert: &str, key: &str, client_ca: Option<&str>) -> Result<ServerConfig> {
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+
+    let mut config = config_builder(client_ca)?
+        .with_single_cert(certs, key)
+        .context("加载服务端证书失败")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// ACME 模式的服务端 TLS 配置，证书由 [`AcmeResolver`] 动态提供，并响应 TLS-ALPN-01 验证
+pub fn acme_server_config(
+    resolver: Arc<AcmeResolver>,
+    client_ca: Option<&str>,
+) -> Result<ServerConfig> {
+    let mut config = config_builder(client_ca)?.with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    Ok(config)
+}
+
+fn config_builder(client_ca: Option<&str>) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>> {
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in load_certs(path)? {
+                roots
+                    .add(ca)
+                    .with_context(|| format!("加载客户端 CA {} 失败", path))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .context("构建客户端证书校验器失败")?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    Ok(builder)
+}
+
+/// wss:// 使用的证书文件
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+}
+
+impl TlsFiles {
+    pub fn load(&self) -> Result<RustlsConfig> {
+        let config = self.server_config()?;
+        self.log_expiry();
+        Ok(RustlsConfig::from_config(Arc::new(config)))
+    }
+
+    fn server_config(&self) -> Result<ServerConfig> {
+        server_config(&self.cert, &self.key, self.client_ca.as_deref())
+    }
+
+    fn log_expiry(&self) {
+        match load_certs(&self.cert)
+            .ok()
+            .and_then(|c| cert_not_after(&c[0]))
+        {
+            Some(not_after) => info!("TLS 证书 {} 有效期至 {}", self.cert, not_after.to_rfc3339()),
+            None => warn!("无法读取 TLS 证书 {} 的有效期", self.cert),
+        }
+    }
+
+    /// 证书文件最近修改时间，用于检测变更
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// 重新加载证书；新配置只影响之后的 TLS 握手，已建立的 WebSocket 会话不受影响。
+    /// 失败时保留旧证书
+    fn reload(&self, config: &RustlsConfig) -> Result<()> {
+        let new_config = self.server_config()?;
+        config.reload_from_config(Arc::new(new_config));
+        info!("TLS 证书已重新加载");
+        self.log_expiry();
+        Ok(())
+    }
+}
+
+/// 在收到 SIGHUP 或证书文件变更时重新加载证书，`interval` 为文件检查间隔 (为 0 时不检查)
+pub fn spawn_reloader(files: TlsFiles, config: RustlsConfig, interval: Duration) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(s) => Some(s),
+            Err(e) => {
+                warn!("注册 SIGHUP 处理失败: {}", e);
+                None
+            }
+        };
+
+        let mut last_modified = files.modified();
+        let mut ticker = (!interval.is_zero()).then(|| tokio::time::interval(interval));
+        if let Some(t) = ticker.as_mut() {
+            t.tick().await;
+        }
+
+        loop {
+            #[cfg(unix)]
+            let sighup = async {
+                match hangup.as_mut() {
+                    Some(s) => {
+                        s.recv().await;
+                    }
+                    None => std::future::pending::<()>().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let sighup = std::future::pending::<()>();
+
+            let poll = async {
+                match ticker.as_mut() {
+                    Some(t) => {
+                        t.tick().await;
+                    }
+                    None => std::future::pending::<()>().await,
+                }
+            };
+
+            // 加载前记录修改时间，加载成功后才更新：文件写到一半时加载失败，
+            // 下次检查仍视为有变更并重试
+            let modified = tokio::select! {
+                _ = sighup => {
+                    info!("收到 SIGHUP，重新加载 TLS 证书");
+                    files.modified()
+                }
+                _ = poll => {
+                    let modified = files.modified();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    info!("检测到 TLS 证书文件变更，重新加载");
+                    modified
+                }
+            };
+            match files.reload(&config) {
+                Ok(()) => last_modified = modified,
+                Err(e) => warn!("TLS 证书重新加载失败，继续使用旧证书: {:#}", e),
+            }
+        }
+    });
+}
+
+/// 读取证书到期时间
+pub fn cert_not_after(der: &[u8]) -> Option<DateTime<Utc>> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+}
+
+/// 在 TLS 握手后提取客户端证书 CN，附加到每个请求的扩展中
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor<DefaultAcceptor>,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = tokio_rustls::server::TlsStream<I>;
+    type Service = AddExtension<S, PeerCert>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let common_name = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| common_name(cert));
+            Ok((stream, AddExtension::new(service, PeerCert { common_name })))
+        })
+    }
+}
+
+/// 读取证书 Subject CN
+fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_string)
+}
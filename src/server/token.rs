@@ -0,0 +1,453 @@
+//! 客户端 Token 注册表
+//!
+//! Token 可在 Token 文件中手工配置，也可通过 `/api/tokens` 在运行时创建、吊销和
+//! 设置过期时间，变更会写回 Token 文件。
+//!
+//! Token 文件格式 (JSON):
+//! ```json
+//! {
+//!   "tokens": [
+//!     {
+//!       "token": "office-secret",
+//!       "name": "办公室",
+//!       "client_pattern": "office-*",
+//!       "port_range": [10000, 10099],
+//!       "tunnel_types": ["tcp"],
+//!       "max_tunnels": 5,
+//!       "expires_at": "2026-12-31T00:00:00Z"
+//!     }
+//!   ]
+//! }
+//! ```
+
+use crate::common::auth;
+use crate::common::protocol::{ErrorCode, TunnelConfig, TunnelType};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tracing::info;
+
+/// `--token` 对应的条目 ID，不写入 Token 文件，不可吊销
+const SHARED_TOKEN_ID: &str = "cli";
+
+/// Token 的授权范围，字段缺省表示不限制
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Entitlement {
+    /// 允许的客户端名称，支持 `*` 通配
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_pattern: Option<String>,
+    /// 允许的服务端端口范围 [起始, 结束]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_range: Option<(u16, u16)>,
+    /// 允许的隧道类型
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_types: Option<Vec<TunnelType>>,
+    /// 最大隧道数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tunnels: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenEntry {
+    /// 手工配置时可省略，加载时生成并写回 Token 文件，保证重启后 ID 不变
+    #[serde(default)]
+    pub id: String,
+    pub token: String,
+    /// 备注名称，仅用于日志
+    #[serde(default)]
+    pub name: String,
+    #[serde(flatten)]
+    pub entitlement: Entitlement,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub revoked: bool,
+}
+
+impl TokenEntry {
+    pub fn is_active(&self) -> bool {
+        !self.revoked && self.expires_at.is_none_or(|t| t > Utc::now())
+    }
+}
+
+/// 握手认证通过后的授权结果
+#[derive(Debug, Clone, Default)]
+pub struct Grant {
+    /// 所用 Token 的 ID，未启用认证时为空
+    pub token_id: Option<String>,
+    pub entitlement: Entitlement,
+    /// wss:// 客户端证书 CN，注册名称必须与之一致
+    pub cert_cn: Option<String>,
+}
+
+/// 新建 Token 的参数
+#[derive(Debug, Deserialize)]
+pub struct NewToken {
+    #[serde(default)]
+    pub name: String,
+    #[serde(flatten)]
+    pub entitlement: Entitlement,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenFile {
+    tokens: Vec<TokenEntry>,
+}
+
+/// Token 注册表：`--token` 共享 Token + `--token-file` 中的 Token
+#[derive(Debug, Default)]
+pub struct TokenRegistry {
+    shared: Option<TokenEntry>,
+    entries: RwLock<Vec<TokenEntry>>,
+    path: Option<PathBuf>,
+}
+
+impl TokenRegistry {
+    /// Token 文件不存在时视为空注册表，首次通过 API 创建 Token 时写入
+    pub fn load(shared_token: Option<String>, token_file: Option<&str>) -> Result<Self> {
+        let shared = shared_token.map(|token| TokenEntry {
+            id: SHARED_TOKEN_ID.to_string(),
+            token,
+            name: "--token".to_string(),
+            entitlement: Entitlement::default(),
+            created_at: None,
+            expires_at: None,
+            revoked: false,
+        });
+
+        let mut entries = Vec::new();
+        if let Some(path) = token_file {
+            if Path::new(path).exists() {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("读取 Token 文件 {} 失败", path))?;
+                let file: TokenFile = serde_json::from_str(&content)
+                    .with_context(|| format!("解析 Token 文件 {} 失败", path))?;
+                entries = file.tokens;
+            }
+            let mut generated = false;
+            for entry in entries.iter_mut().filter(|e| e.id.is_empty()) {
+                entry.id = new_token_id();
+                generated = true;
+            }
+            if generated {
+                save(Path::new(path), &entries)
+                    .with_context(|| format!("写回 Token 文件 {} 失败", path))?;
+                info!("已为 Token 文件中缺少 id 的 Token 生成 ID");
+            }
+        }
+
+        Ok(Self {
+            shared,
+            entries: RwLock::new(entries),
+            path: token_file.map(PathBuf::from),
+        })
+    }
+
+    /// 未配置 --token 和 --token-file 时不做认证
+    pub fn is_enabled(&self) -> bool {
+        self.shared.is_some() || self.path.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.iter().count() + self.entries.read().unwrap().len()
+    }
+
+    /// 校验挑战应答并返回对应 Token 的授权结果，已吊销或过期的 Token 不参与匹配
+    pub fn authorize(
+        &self,
+        nonce: &str,
+        client_name: &str,
+        mac: Option<&str>,
+    ) -> Result<Grant, (ErrorCode, String)> {
+        if !self.is_enabled() {
+            return Ok(Grant::default());
+        }
+        let mac = mac.ok_or_else(|| (ErrorCode::AuthFailed, "认证失败: 缺少 Token".to_string()))?;
+        let entries = self.entries.read().unwrap();
+        self.shared
+            .iter()
+            .chain(entries.iter())
+            .filter(|e| e.is_active())
+            .find(|e| auth::verify(&e.token, nonce, client_name, mac))
+            .map(|e| Grant {
+                token_id: Some(e.id.clone()),
+                entitlement: e.entitlement.clone(),
+                cert_cn: None,
+            })
+            .ok_or_else(|| (ErrorCode::AuthFailed, "认证失败: Token 无效".to_string()))
+    }
+
+    /// 列出全部 Token（含 `--token`）
+    pub fn list(&self) -> Vec<TokenEntry> {
+        let entries = self.entries.read().unwrap();
+        self.shared.iter().chain(entries.iter()).cloned().collect()
+    }
+
+    /// 新建 Token 并写回文件，返回的条目包含完整 Token（仅此一次）
+    pub fn create(&self, req: NewToken) -> Result<TokenEntry, String> {
+        let entry = TokenEntry {
+            id: new_token_id(),
+            token: generate_token(),
+            name: req.name,
+            entitlement: req.entitlement,
+            created_at: Some(Utc::now()),
+            expires_at: req.expires_at,
+            revoked: false,
+        };
+        self.update(|entries| {
+            entries.push(entry.clone());
+            Ok(())
+        })?;
+        Ok(entry)
+    }
+
+    /// 吊销 Token
+    pub fn revoke(&self, id: &str) -> Result<(), String> {
+        self.update(|entries| {
+            let entry = find_mut(entries, id)?;
+            entry.revoked = true;
+            Ok(())
+        })
+    }
+
+    /// 设置 Token 过期时间
+    pub fn set_expiry(&self, id: &str, expires_at: DateTime<Utc>) -> Result<(), String> {
+        self.update(|entries| {
+            let entry = find_mut(entries, id)?;
+            entry.expires_at = Some(expires_at);
+            Ok(())
+        })
+    }
+
+    /// 修改 Token 列表并写回文件，写入失败时回滚
+    fn update<F>(&self, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Vec<TokenEntry>) -> Result<(), String>,
+    {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| "未配置 --token-file，无法在运行时管理 Token".to_string())?;
+        let mut entries = self.entries.write().unwrap();
+        let mut updated = entries.clone();
+        f(&mut updated)?;
+        save(path, &updated).map_err(|e| format!("写入 Token 文件失败: {}", e))?;
+        *entries = updated;
+        Ok(())
+    }
+}
+
+fn find_mut<'a>(entries: &'a mut [TokenEntry], id: &str) -> Result<&'a mut TokenEntry, String> {
+    if id == SHARED_TOKEN_ID {
+        return Err("--token 指定的 Token 不能在运行时修改".to_string());
+    }
+    entries
+        .iter_mut()
+        .find(|e| e.id == id)
+        .ok_or_else(|| format!("Token {} 不存在", id))
+}
+
+/// 先写临时文件再重命名，避免写入中断损坏 Token 文件；文件含 Token 明文，仅所有者可读写
+fn save(path: &Path, entries: &[TokenEntry]) -> std::io::Result<()> {
+    let file = TokenFile {
+        tokens: entries.to_vec(),
+    };
+    let content = serde_json::to_string_pretty(&file)?;
+    // 直接追加后缀，`with_extension` 在文件名本身以 .tmp 结尾时会得到原路径
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    // 上次中断留下的临时文件可能权限过宽，重新创建
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut out = options.open(&tmp)?;
+    out.write_all(content.as_bytes())?;
+    out.sync_all()?;
+    drop(out);
+    std::fs::rename(&tmp, path)
+}
+
+fn new_token_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn generate_token() -> String {
+    let mut buf = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .expect("系统随机数生成失败");
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+/// 列表展示时隐藏 Token 明文
+pub fn mask_token(token: &str) -> String {
+    let prefix: String = token.chars().take(4).collect();
+    format!("{}****", prefix)
+}
+
+impl Entitlement {
+    pub fn check_client_name(&self, name: &str) -> Result<(), String> {
+        match &self.client_pattern {
+            Some(pattern) if !wildcard_match(pattern, name) => Err(format!(
+                "客户端名称 {} 不符合 Token 允许的模式 {}",
+                name, pattern
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// 检查新隧道是否在授权范围内，`existing` 为客户端已有隧道数
+    pub fn check_tunnel(&self, config: &TunnelConfig, existing: usize) -> Result<(), String> {
+        if let Some(max) = self.max_tunnels {
+            if existing >= max {
+                return Err(format!("隧道数量超出 Token 限制 ({})", max));
+            }
+        }
+        if let Some(types) = &self.tunnel_types {
+            if !types.contains(&config.tunnel_type) {
+                return Err(format!("Token 不允许 {:?} 类型的隧道", config.tunnel_type));
+            }
+        }
+        if let (Some((start, end)), Some(port)) = (self.port_range, config.remote_port) {
+            if port < start || port > end {
+                return Err(format!(
+                    "服务端端口 {} 超出 Token 允许范围 {}-{}",
+                    port, start, end
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 与服务端全局端口范围取交集
+    pub fn port_range(&self, port_start: u16, port_end: u16) -> (u16, u16) {
+        match self.port_range {
+            Some((start, end)) => (start.max(port_start), end.min(port_end)),
+            None => (port_start, port_end),
+        }
+    }
+}
+
+/// 简单通配匹配，`*` 匹配任意长度字符
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && p[pi] != '*' && p[pi] == t[ti] {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(remote_port: Option<u16>) -> TunnelConfig {
+        TunnelConfig {
+            tunnel_type: TunnelType::Tcp,
+            local_addr: "127.0.0.1".into(),
+            local_port: 22,
+            remote_port,
+            name: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            e2e: false,
+            secret: false,
+            secret_key: None,
+            compress: None,
+        }
+    }
+
+    #[test]
+    fn wildcard_positions() {
+        assert!(wildcard_match("*-office", "bj-office"));
+        assert!(wildcard_match("office-*-pc", "office-12-pc"));
+        assert!(wildcard_match("office-*", "office-1"));
+        assert!(wildcard_match("office-*", "office-"));
+        assert!(wildcard_match("a*b*c", "aXXbYYc"));
+        assert!(!wildcard_match("*-office", "bj-office2"));
+        assert!(!wildcard_match("office-*-pc", "office-12-mac"));
+        assert!(!wildcard_match("office-*", "home-1"));
+    }
+
+    #[test]
+    fn wildcard_empty() {
+        assert!(wildcard_match("", ""));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("**", ""));
+        assert!(!wildcard_match("", "a"));
+        assert!(!wildcard_match("a*", ""));
+    }
+
+    #[test]
+    fn port_range_edges() {
+        let ent = Entitlement {
+            port_range: Some((10000, 10099)),
+            ..Default::default()
+        };
+        assert!(ent.check_tunnel(&tcp(Some(10000)), 0).is_ok());
+        assert!(ent.check_tunnel(&tcp(Some(10099)), 0).is_ok());
+        assert!(ent.check_tunnel(&tcp(Some(9999)), 0).is_err());
+        assert!(ent.check_tunnel(&tcp(Some(10100)), 0).is_err());
+        assert!(ent.check_tunnel(&tcp(None), 0).is_ok());
+        assert_eq!(ent.port_range(10050, 20000), (10050, 10099));
+    }
+
+    #[test]
+    fn max_tunnels_counts_existing() {
+        let ent = Entitlement {
+            max_tunnels: Some(2),
+            ..Default::default()
+        };
+        assert!(ent.check_tunnel(&tcp(None), 0).is_ok());
+        assert!(ent.check_tunnel(&tcp(None), 1).is_ok());
+        assert!(ent.check_tunnel(&tcp(None), 2).is_err());
+        assert!(Entitlement {
+            max_tunnels: Some(0),
+            ..Default::default()
+        }
+        .check_tunnel(&tcp(None), 0)
+        .is_err());
+    }
+
+    #[test]
+    fn tunnel_type_filter() {
+        let ent = Entitlement {
+            tunnel_types: Some(vec![TunnelType::Tcp]),
+            ..Default::default()
+        };
+        assert!(ent.check_tunnel(&tcp(None), 0).is_ok());
+        let udp = TunnelConfig {
+            tunnel_type: TunnelType::Udp,
+            ..tcp(None)
+        };
+        assert!(ent.check_tunnel(&udp, 0).is_err());
+    }
+}